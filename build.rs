@@ -0,0 +1,7 @@
+fn main() {
+    // Expose the target triple so /api/bench results can report what they ran on.
+    println!(
+        "cargo:rustc-env=TARGET_TRIPLE={}",
+        std::env::var("TARGET").unwrap_or_else(|_| "unknown".to_string())
+    );
+}
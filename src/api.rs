@@ -0,0 +1,103 @@
+use actix_web::error::JsonPayloadError;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+
+/// Largest JSON body accepted by `/api` routes.
+const JSON_LIMIT_BYTES: usize = 64 * 1024;
+
+/// Error returned by `/api` routes, rendered as
+/// `{"error": {"code": "...", "message": "..."}}` so JSON clients never have
+/// to parse a plain-text body.
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// A 500 for unexpected failures. The cause is logged, not sent.
+    pub fn internal(err: impl std::fmt::Display) -> Self {
+        log::error!("api request failed: {}", err);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", "Something went wrong")
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(serde_json::json!({
+            "error": { "code": self.code, "message": self.message }
+        }))
+    }
+}
+
+/// JSON extractor settings for the `/api` scope: a body size limit, and
+/// malformed or oversized bodies answered with an `ApiError` instead of
+/// Actix's plain-text 400.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(JSON_LIMIT_BYTES)
+        .error_handler(|err, _req| {
+            let api_error = match &err {
+                JsonPayloadError::OverflowKnownLength { .. } | JsonPayloadError::Overflow { .. } => {
+                    ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", err.to_string())
+                }
+                JsonPayloadError::ContentType => ApiError::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "invalid_json",
+                    "Expected a Content-Type of application/json",
+                ),
+                _ => ApiError::new(StatusCode::BAD_REQUEST, "invalid_json", err.to_string()),
+            };
+            api_error.into()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::Value;
+    use crate::config::Config;
+    use crate::test_support::client;
+
+    fn patch_me(content_type: &str, body: impl Into<String>) -> TestRequest {
+        TestRequest::patch()
+            .uri("/api/me")
+            .insert_header(("content-type", content_type))
+            .set_payload(body.into())
+    }
+
+    #[actix_web::test]
+    async fn bad_json_bodies_get_the_error_envelope() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+
+        let oversized = format!(r#"{{"bio": "{}"}}"#, "x".repeat(JSON_LIMIT_BYTES));
+        let cases = [
+            (patch_me("application/json", "{\"bio\": "), StatusCode::BAD_REQUEST, "invalid_json"),
+            (patch_me("application/json", oversized), StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+            (patch_me("text/plain", "{}"), StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid_json"),
+        ];
+        for (request, status, code) in cases {
+            let reply = client.send(request).await;
+            assert_eq!(reply.status, status, "{}", reply.body);
+            let body: Value = serde_json::from_str(&reply.body).unwrap();
+            assert_eq!(body["error"]["code"], code);
+            assert!(body["error"]["message"].as_str().is_some_and(|message| !message.is_empty()));
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use actix_files::NamedFile;
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::{web, HttpRequest, Responder, Result};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tera::{Function, Value};
+
+/// URL prefix the static directory is served under.
+pub const ASSETS_PREFIX: &str = "/assets";
+
+/// Content-hashed URLs for the files in the static directory, computed once
+/// at startup. A hashed URL changes whenever the file's content does, so it
+/// can be cached forever; files edited while the server runs keep their old
+/// hash until a restart.
+#[derive(Debug, Default)]
+pub struct AssetManifest {
+    /// `/assets/css/app.css` -> `/assets/css/app.<hash>.css`
+    urls: HashMap<String, String>,
+    /// `/assets/css/app.<hash>.css` -> file on disk
+    files: HashMap<String, PathBuf>,
+}
+
+impl AssetManifest {
+    /// Hashes every file under `static_dir`. Unreadable files and a missing
+    /// directory are skipped; their URLs are then left unhashed.
+    pub fn build(static_dir: &str) -> Self {
+        let mut manifest = Self::default();
+        manifest.add_dir(Path::new(static_dir), ASSETS_PREFIX);
+        manifest
+    }
+
+    fn add_dir(&mut self, dir: &Path, url_prefix: &str) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let url = format!("{}/{}", url_prefix, name);
+
+            if path.is_dir() {
+                self.add_dir(&path, &url);
+                continue;
+            }
+
+            match fs::read(&path) {
+                Ok(content) => {
+                    let hashed = format!("{}/{}", url_prefix, hashed_name(&name, &content));
+                    self.files.insert(hashed.clone(), path);
+                    self.urls.insert(url, hashed);
+                }
+                Err(e) => log::warn!("Could not hash asset {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// The hashed URL for an asset path, or the path unchanged when the file
+    /// isn't known.
+    pub fn url(&self, path: &str) -> String {
+        self.urls.get(path).cloned().unwrap_or_else(|| path.to_string())
+    }
+
+    /// The file behind a hashed URL.
+    pub fn file_for(&self, hashed_url: &str) -> Option<&Path> {
+        self.files.get(hashed_url).map(PathBuf::as_path)
+    }
+}
+
+/// `app.min.css` -> `app.min.<hash>.css`, using the first 8 hex digits of
+/// the content's MD5.
+fn hashed_name(name: &str, content: &[u8]) -> String {
+    let digest = Md5::digest(content);
+    let hash: String = digest.iter().take(4).map(|byte| format!("{:02x}", byte)).collect();
+
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}.{}.{}", stem, hash, ext),
+        _ => format!("{}.{}", name, hash),
+    }
+}
+
+/// Serves a hashed asset URL with far-future, immutable caching. Only routed
+/// for URLs in the manifest; everything else under `/assets` falls through to
+/// the plain file service.
+pub async fn serve_hashed(req: HttpRequest, assets: web::Data<AssetManifest>) -> Result<impl Responder> {
+    let file = assets
+        .file_for(req.path())
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Not found"))?;
+
+    Ok(NamedFile::open_async(file).await?.customize().insert_header(CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(31_536_000),
+        CacheDirective::Extension("immutable".to_string(), None),
+    ])))
+}
+
+/// `{{ asset(path="/assets/css/app.css") }}` returns the content-hashed URL
+/// of a static file, falling back to the given path for unknown files.
+pub struct AssetFn(pub web::Data<AssetManifest>);
+
+impl Function for AssetFn {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let path = args
+            .get("path")
+            .and_then(Value::as_str)
+            .ok_or_else(|| tera::Error::msg("asset: missing string argument `path`"))?;
+        Ok(Value::String(self.0.url(path)))
+    }
+
+    // Output is a URL written by template authors or derived from one, and
+    // escaping would turn its slashes into entities
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    const CSS: &[u8] = b"body { margin: 0 }";
+
+    /// A fresh static directory holding `css/app.css`.
+    fn static_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("assets-test-{}", uuid::Uuid::new_v4().simple()));
+        fs::create_dir_all(dir.join("css")).unwrap();
+        fs::write(dir.join("css/app.css"), CSS).unwrap();
+        dir
+    }
+
+    fn expected_url() -> String {
+        let hash: String = Md5::digest(CSS).iter().take(4).map(|byte| format!("{:02x}", byte)).collect();
+        format!("/assets/css/app.{}.css", hash)
+    }
+
+    #[test]
+    fn asset_returns_the_hashed_url_for_known_files() {
+        let dir = static_dir();
+        let asset = AssetFn(web::Data::new(AssetManifest::build(dir.to_str().unwrap())));
+
+        let args = HashMap::from([("path".to_string(), Value::from("/assets/css/app.css"))]);
+        assert_eq!(asset.call(&args).unwrap(), Value::String(expected_url()));
+
+        let args = HashMap::from([("path".to_string(), Value::from("/assets/css/missing.css"))]);
+        assert_eq!(asset.call(&args).unwrap(), Value::from("/assets/css/missing.css"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hashed_name_keeps_the_extension_last() {
+        assert_eq!(hashed_name("app.min.css", b""), "app.min.d41d8cd9.css");
+        assert_eq!(hashed_name("LICENSE", b""), "LICENSE.d41d8cd9");
+        assert_eq!(hashed_name(".htaccess", b""), ".htaccess.d41d8cd9");
+    }
+
+    #[actix_web::test]
+    async fn hashed_urls_are_served_immutable() {
+        let dir = static_dir();
+        let manifest = web::Data::new(AssetManifest::build(dir.to_str().unwrap()));
+        let app = init_service(
+            App::new()
+                .app_data(manifest)
+                .route("/assets/{path:.*}", web::get().to(serve_hashed)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri(&expected_url()).to_request()).await;
+        assert_eq!(res.status(), 200);
+        let cache_control = res.headers().get("cache-control").unwrap().to_str().unwrap();
+        assert!(cache_control.contains("immutable"), "{cache_control}");
+        assert_eq!(read_body(res).await, CSS);
+
+        let res = call_service(&app, TestRequest::get().uri("/assets/css/app.css").to_request()).await;
+        assert_eq!(res.status(), 404);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,64 +1,618 @@
-use actix_web::{dev::Payload, Error, FromRequest, HttpRequest, HttpResponse, Result};
+use actix_web::{dev::Payload, error::InternalError, web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, Result};
+use actix_web::body::MessageBody;
+use actix_web::cookie::time;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, SET_COOKIE};
+use actix_web::middleware::Next;
 use actix_session::Session;
-use futures_util::future::{Ready, ready};
-use serde_json;
+use chrono::Utc;
+use futures_util::future::{LocalBoxFuture, Ready, ready};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tera::Context;
+use crate::config::Config;
+use crate::csrf::{verify_csrf_header, with_csrf};
+use crate::database::Database;
 use crate::models::SessionUser;
 
 pub struct AuthUser(pub SessionUser);
 
+/// Shape version of the `user` payload stored in the session. Bump it whenever
+/// `SessionUser` changes shape and add the matching step to
+/// `read_session_payload`, so cookies issued before a deploy keep working.
+///
+/// - 1: unversioned payload (id, email, username, full_name, avatar_url),
+///   stored as a JSON string
+/// - 2: same fields, tagged with `v`
+/// - 3: `full_name` renamed to `display_name`
+/// - 4: added `timezone`
+const SESSION_USER_VERSION: u64 = 4;
+
+#[derive(Serialize)]
+struct VersionedSessionUser<'a> {
+    v: u64,
+    #[serde(flatten)]
+    user: &'a SessionUser,
+}
+
+/// Decodes a stored session payload, upgrading older versions to the current
+/// shape. Returns the user and whether an upgrade was applied.
+fn read_session_payload(mut payload: Value) -> Option<(SessionUser, bool)> {
+    // v1 cookies hold the user pre-serialized, as a JSON string
+    if let Value::String(json) = &payload {
+        payload = serde_json::from_str(json).ok()?;
+    }
+    let version = payload.get("v").and_then(Value::as_u64).unwrap_or(1);
+
+    // Upgrade steps go here, oldest first, filling in defaults for fields
+    // added since the payload was written.
+    // v1 -> v2 only added the version tag, so v1 payloads decode as-is.
+    if version < 3 {
+        let full_name = payload.get("full_name").cloned()?;
+        payload["display_name"] = full_name;
+    }
+    if version < 4 {
+        payload["timezone"] = Value::from("UTC");
+    }
+
+    let user = serde_json::from_value(payload).ok()?;
+    Some((user, version < SESSION_USER_VERSION))
+}
+
+/// Name of the session cookie.
+pub const SESSION_COOKIE_NAME: &str = "id";
+
+const REMEMBER_ME_SESSION_KEY: &str = "remember_me";
+
+/// How long a login lasts, chosen by the "remember me" checkbox.
+///
+/// - `Short`: a browser-session cookie, capped at
+///   `SESSION_MAX_LIFETIME_MINUTES`.
+/// - `Long`: a persistent cookie, capped at `REMEMBER_ME_DAYS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RememberMePolicy {
+    Short,
+    Long,
+}
+
+impl RememberMePolicy {
+    pub fn from_form(remember_me: Option<bool>) -> Self {
+        if remember_me.unwrap_or(false) {
+            Self::Long
+        } else {
+            Self::Short
+        }
+    }
+
+    /// Absolute session lifetime in seconds.
+    pub fn max_lifetime_secs(self, config: &Config) -> i64 {
+        match self {
+            Self::Short => config.session_max_lifetime_minutes * 60,
+            Self::Long => config.remember_me_days * 24 * 60 * 60,
+        }
+    }
+
+    /// `Max-Age` of the session cookie; `None` keeps it a browser-session
+    /// cookie that is dropped when the browser closes.
+    pub fn cookie_max_age(self, config: &Config) -> Option<time::Duration> {
+        match self {
+            Self::Short => None,
+            Self::Long => Some(time::Duration::seconds(self.max_lifetime_secs(config))),
+        }
+    }
+}
+
+enum SessionState {
+    Active(SessionUser),
+    Expired,
+    Anonymous,
+}
+
+/// Reads the logged-in user from the session, enforcing the absolute session
+/// lifetime. Sessions past the cap are purged; sessions from before the cap
+/// existed have no creation time, so their lifetime starts now.
+fn load_session_user(req: &HttpRequest) -> SessionState {
+    let session = match Session::extract(req).into_inner() {
+        Ok(session) => session,
+        Err(_) => return SessionState::Anonymous,
+    };
+
+    let user = match session.get::<Value>("user") {
+        Ok(Some(payload)) => match read_session_payload(payload) {
+            Some((user, upgraded)) => {
+                // Rewrite older payloads so the upgrade only happens once
+                if upgraded {
+                    let _ = update_session_user(&session, user.clone());
+                }
+                user
+            }
+            None => return SessionState::Anonymous,
+        },
+        _ => return SessionState::Anonymous,
+    };
+
+    // Sessions from before the policy existed are treated as short
+    let policy = session
+        .get::<RememberMePolicy>(REMEMBER_ME_SESSION_KEY)
+        .ok()
+        .flatten()
+        .unwrap_or(RememberMePolicy::Short);
+    req.extensions_mut().insert(policy);
+
+    if let Some(config) = req.app_data::<web::Data<Config>>() {
+        let now = Utc::now().timestamp();
+        let created_at = match session.get::<i64>("session_created_at").ok().flatten() {
+            Some(created_at) => created_at,
+            None => {
+                let _ = session.insert("session_created_at", now);
+                now
+            }
+        };
+
+        if now - created_at >= policy.max_lifetime_secs(config) {
+            session.purge();
+            return SessionState::Expired;
+        }
+    }
+
+    SessionState::Active(user)
+}
+
 impl FromRequest for AuthUser {
     type Error = Error;
     type Future = Ready<Result<Self, Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        let session = Session::extract(req).into_inner();
-        
-        if let Ok(session) = session {
-            if let Ok(Some(user_json)) = session.get::<String>("user") {
-                if let Ok(user) = serde_json::from_str::<SessionUser>(&user_json) {
-                    return ready(Ok(AuthUser(user)));
-                }
+        match load_session_user(req) {
+            SessionState::Active(user) => ready(Ok(AuthUser(user))),
+            SessionState::Expired => {
+                let response = HttpResponse::Found()
+                    .insert_header(("location", "/login?msg=session_expired"))
+                    .finish();
+                ready(Err(InternalError::from_response("Session expired", response).into()))
+            }
+            SessionState::Anonymous => {
+                ready(Err(actix_web::error::ErrorUnauthorized("Not authenticated")))
             }
         }
-        
-        ready(Err(actix_web::error::ErrorUnauthorized("Not authenticated")))
     }
 }
 
 pub struct OptionalAuthUser(pub Option<SessionUser>);
 
+/// An `AuthUser` whose primary email is verified, for sensitive account
+/// changes. Only enforced with `REQUIRE_VERIFIED_EMAIL`; the status is read
+/// from the database on every request rather than from the session, so a
+/// verification takes effect immediately. Unverified users are sent back to
+/// `/profile` with a notice; verifying is an operator task for now (the
+/// `verify-email` command).
+pub struct VerifiedUser(pub SessionUser);
+
+impl FromRequest for VerifiedUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = AuthUser::from_request(req, payload).into_inner();
+        let config = req.app_data::<web::Data<Config>>().cloned();
+        let db = req.app_data::<web::Data<Database>>().cloned();
+
+        Box::pin(async move {
+            let AuthUser(user) = user?;
+            let (Some(config), Some(db)) = (config, db) else {
+                return Ok(VerifiedUser(user));
+            };
+            if !config.require_verified_email {
+                return Ok(VerifiedUser(user));
+            }
+
+            let verified = db
+                .primary_email_verified(&user.id)
+                .await
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            if verified {
+                return Ok(VerifiedUser(user));
+            }
+
+            let response = HttpResponse::Found()
+                .insert_header(("location", "/profile?msg=email_unverified"))
+                .finish();
+            Err(InternalError::from_response("Email not verified", response).into())
+        })
+    }
+}
+
 impl FromRequest for OptionalAuthUser {
     type Error = Error;
     type Future = Ready<Result<Self, Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        let session = Session::extract(req).into_inner();
-        
-        if let Ok(session) = session {
-            if let Ok(Some(user_json)) = session.get::<String>("user") {
-                if let Ok(user) = serde_json::from_str::<SessionUser>(&user_json) {
-                    return ready(Ok(OptionalAuthUser(Some(user))));
-                }
-            }
+        match load_session_user(req) {
+            SessionState::Active(user) => ready(Ok(OptionalAuthUser(Some(user)))),
+            SessionState::Expired | SessionState::Anonymous => ready(Ok(OptionalAuthUser(None))),
         }
-        
-        ready(Ok(OptionalAuthUser(None)))
     }
 }
 
-pub async fn logout(session: Session) -> Result<HttpResponse> {
+/// Exposes the login state to templates: `user` when logged in, and an
+/// `is_authenticated` flag that is always present. Logged-in pages also get
+/// the CSRF token for the logout button; anonymous visitors don't, so public
+/// pages never start a session just to render.
+pub fn inject_auth(ctx: &mut Context, user: &OptionalAuthUser, session: &Session) {
+    ctx.insert("is_authenticated", &user.0.is_some());
+    if let Some(user) = &user.0 {
+        ctx.insert("user", user);
+        // Without a token the logout button falls back to a confirm page
+        let _ = with_csrf(ctx, session);
+    }
+}
+
+const SUDO_SESSION_KEY: &str = "sudo_until";
+
+/// How long re-entering the password unlocks sensitive actions.
+const SUDO_WINDOW_MINUTES: i64 = 5;
+
+/// Marks the session as having just re-entered its password, unlocking
+/// sensitive actions for `SUDO_WINDOW_MINUTES`.
+pub fn grant_sudo(session: &Session) -> Result<(), actix_web::Error> {
+    let until = Utc::now().timestamp() + SUDO_WINDOW_MINUTES * 60;
+    session.insert(SUDO_SESSION_KEY, until)
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Session storage error: {}", e)))?;
+
+    Ok(())
+}
+
+/// Whether the password was re-entered recently enough for a sensitive
+/// action. Expired grants are dropped from the session.
+pub fn has_sudo(session: &Session) -> bool {
+    match session.get::<i64>(SUDO_SESSION_KEY).ok().flatten() {
+        Some(until) if Utc::now().timestamp() < until => true,
+        Some(_) => {
+            session.remove(SUDO_SESSION_KEY);
+            false
+        }
+        None => false,
+    }
+}
+
+pub fn end_session(session: &Session) {
     session.clear();
-    Ok(HttpResponse::Found()
-        .insert_header(("location", "/login?msg=logged_out"))
-        .finish())
 }
 
-pub fn login_user(session: &Session, user: SessionUser) -> Result<(), actix_web::Error> {
-    let user_json = serde_json::to_string(&user)
-        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Session serialization error: {}", e)))?;
-    
-    session.insert("user", user_json)
+/// Logout for API clients: same session teardown, but a JSON body instead
+/// of a redirect. The session cookie alone isn't enough, since a cross-site
+/// form could send it; the CSRF token must come in `X-CSRF-Token`.
+pub async fn api_logout(req: HttpRequest, session: Session) -> Result<HttpResponse> {
+    verify_csrf_header(&req, &session)?;
+    end_session(&session);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "ok": true })))
+}
+
+pub fn login_user(
+    req: &HttpRequest,
+    session: &Session,
+    user: SessionUser,
+    policy: RememberMePolicy,
+) -> Result<(), actix_web::Error> {
+    update_session_user(session, user)?;
+    session.insert("session_created_at", Utc::now().timestamp())
         .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Session storage error: {}", e)))?;
+    session.insert(REMEMBER_ME_SESSION_KEY, policy)
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Session storage error: {}", e)))?;
+    req.extensions_mut().insert(policy);
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Gives the session cookie the `Max-Age` of the request's remember-me
+/// policy. The session middleware only issues browser-session cookies, so
+/// this wraps it and adjusts the cookie on the way out. The policy is
+/// recorded on the request by `login_user` and whenever the session user is
+/// loaded; responses without one keep the browser-session cookie.
+pub async fn persist_remembered_session(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut res = next.call(req).await?;
+
+    let policy = res.request().extensions().get::<RememberMePolicy>().copied();
+    let max_age = match (policy, res.request().app_data::<web::Data<Config>>()) {
+        (Some(policy), Some(config)) => policy.cookie_max_age(config),
+        _ => None,
+    };
+    let Some(max_age) = max_age else {
+        return Ok(res);
+    };
+
+    // Leave removal cookies (which already carry a Max-Age) alone
+    let cookie = res
+        .response()
+        .cookies()
+        .find(|cookie| cookie.name() == SESSION_COOKIE_NAME && cookie.max_age().is_none())
+        .map(|cookie| cookie.into_owned());
+
+    if let Some(mut cookie) = cookie {
+        cookie.set_max_age(max_age);
+        // Percent-encoded, like the session middleware writes it
+        let value = HeaderValue::from_str(&cookie.encoded().to_string())
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        res.response_mut().del_cookie(SESSION_COOKIE_NAME);
+        res.headers_mut().append(SET_COOKIE, value);
+    }
+
+    Ok(res)
+}
+
+/// Replaces the user stored in an existing session without resetting its lifetime.
+pub fn update_session_user(session: &Session, user: SessionUser) -> Result<(), actix_web::Error> {
+    // The session serializes values itself, so store the struct directly
+    // rather than a pre-encoded JSON string.
+    let payload = VersionedSessionUser { v: SESSION_USER_VERSION, user: &user };
+    session.insert("user", &payload)
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Session storage error: {}", e)))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::Cookie;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{TestRequest, call_and_read_body_json, call_service, init_service, read_body_json};
+    use actix_web::App;
+    use crate::test_support::{client, session_middleware};
+
+    fn sample_user() -> SessionUser {
+        SessionUser {
+            id: "user-1".to_string(),
+            email: "ada@example.com".to_string(),
+            username: "ada".to_string(),
+            display_name: "Ada Lovelace".to_string(),
+            avatar_url: None,
+            timezone: "Europe/London".to_string(),
+        }
+    }
+
+    async fn log_in(req: HttpRequest, session: Session) -> HttpResponse {
+        login_user(&req, &session, sample_user(), RememberMePolicy::Short).unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    async fn me(user: AuthUser) -> HttpResponse {
+        HttpResponse::Ok().json(user.0)
+    }
+
+    /// Stores each key of the body in the session as-is, to forge sessions
+    /// written by older versions of the app.
+    async fn seed(session: Session, entries: web::Json<serde_json::Map<String, Value>>) -> HttpResponse {
+        for (key, value) in entries.into_inner() {
+            session.insert(key, value).unwrap();
+        }
+        HttpResponse::Ok().finish()
+    }
+
+    async fn created_at(session: Session) -> HttpResponse {
+        HttpResponse::Ok().json(session.get::<i64>("session_created_at").unwrap())
+    }
+
+    async fn sudo(session: Session) -> HttpResponse {
+        HttpResponse::Ok().json(serde_json::json!({
+            "sudo": has_sudo(&session),
+            "until": session.get::<i64>(SUDO_SESSION_KEY).unwrap(),
+        }))
+    }
+
+    async fn test_app() -> impl actix_web::dev::Service<
+        actix_http::Request,
+        Response = ServiceResponse,
+        Error = Error,
+    > {
+        init_service(
+            App::new()
+                .app_data(web::Data::new(Config::for_tests()))
+                .wrap(session_middleware())
+                .route("/login", web::post().to(log_in))
+                .route("/seed", web::post().to(seed))
+                .route("/me", web::get().to(me))
+                .route("/created_at", web::get().to(created_at))
+                .route("/sudo", web::get().to(sudo)),
+        )
+        .await
+    }
+
+    fn versioned(user: &SessionUser) -> Value {
+        serde_json::to_value(VersionedSessionUser { v: SESSION_USER_VERSION, user }).unwrap()
+    }
+
+    fn session_cookie(res: &ServiceResponse) -> Cookie<'static> {
+        res.response()
+            .cookies()
+            .find(|cookie| cookie.name() == SESSION_COOKIE_NAME)
+            .expect("session cookie")
+            .into_owned()
+    }
+
+    #[actix_web::test]
+    async fn session_user_round_trips_through_the_cookie() {
+        let app = test_app().await;
+
+        let res = call_service(&app, TestRequest::post().uri("/login").to_request()).await;
+        let cookie = session_cookie(&res);
+
+        let req = TestRequest::get().uri("/me").cookie(cookie).to_request();
+        let user: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(user, serde_json::to_value(sample_user()).unwrap());
+    }
+
+    #[actix_web::test]
+    async fn sessions_past_the_lifetime_cap_expire() {
+        let app = test_app().await;
+        let thirteen_hours_ago = Utc::now().timestamp() - 13 * 60 * 60;
+        let req = TestRequest::post()
+            .uri("/seed")
+            .set_json(serde_json::json!({
+                "user": versioned(&sample_user()),
+                "session_created_at": thirteen_hours_ago,
+            }))
+            .to_request();
+        let cookie = session_cookie(&call_service(&app, req).await);
+
+        let req = TestRequest::get().uri("/me").cookie(cookie).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::FOUND);
+        assert_eq!(res.headers().get("location").unwrap(), "/login?msg=session_expired");
+    }
+
+    #[actix_web::test]
+    async fn sessions_without_a_creation_time_are_backfilled() {
+        let app = test_app().await;
+        let req = TestRequest::post()
+            .uri("/seed")
+            .set_json(serde_json::json!({ "user": versioned(&sample_user()) }))
+            .to_request();
+        let cookie = session_cookie(&call_service(&app, req).await);
+
+        let before = Utc::now().timestamp();
+        let req = TestRequest::get().uri("/me").cookie(cookie).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+        let cookie = session_cookie(&res);
+
+        let req = TestRequest::get().uri("/created_at").cookie(cookie).to_request();
+        let created_at: Option<i64> = call_and_read_body_json(&app, req).await;
+        assert!(created_at.is_some_and(|created_at| created_at >= before));
+    }
+
+    /// The `user` value as the baseline app stored it: the unversioned
+    /// payload serialized to a string.
+    fn baseline_payload() -> Value {
+        let user = serde_json::json!({
+            "id": "user-1",
+            "email": "ada@example.com",
+            "username": "ada",
+            "full_name": "Ada Lovelace",
+            "avatar_url": null,
+        });
+        Value::String(user.to_string())
+    }
+
+    #[test]
+    fn older_payloads_upgrade_with_defaults() {
+        let expected = SessionUser { timezone: "UTC".to_string(), ..sample_user() };
+
+        let (user, upgraded) = read_session_payload(baseline_payload()).unwrap();
+        assert_eq!(user, expected);
+        assert!(upgraded);
+
+        let v2 = serde_json::json!({
+            "v": 2, "id": "user-1", "email": "ada@example.com", "username": "ada",
+            "full_name": "Ada Lovelace", "avatar_url": null,
+        });
+        assert_eq!(read_session_payload(v2).unwrap(), (expected, true));
+
+        let current = versioned(&sample_user());
+        assert_eq!(read_session_payload(current).unwrap(), (sample_user(), false));
+        assert!(read_session_payload(Value::String("not json".to_string())).is_none());
+    }
+
+    #[actix_web::test]
+    async fn baseline_cookies_stay_logged_in() {
+        let app = test_app().await;
+        let req = TestRequest::post()
+            .uri("/seed")
+            .set_json(serde_json::json!({ "user": baseline_payload() }))
+            .to_request();
+        let cookie = session_cookie(&call_service(&app, req).await);
+
+        let expected = SessionUser { timezone: "UTC".to_string(), ..sample_user() };
+        let req = TestRequest::get().uri("/me").cookie(cookie).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+        // The upgraded payload is written back, and reads as current
+        let cookie = session_cookie(&res);
+        let user: SessionUser = read_body_json(res).await;
+        assert_eq!(user, expected);
+
+        let req = TestRequest::get().uri("/me").cookie(cookie).to_request();
+        let user: SessionUser = call_and_read_body_json(&app, req).await;
+        assert_eq!(user, expected);
+    }
+
+    async fn sudo_state(
+        app: &impl actix_web::dev::Service<actix_http::Request, Response = ServiceResponse, Error = Error>,
+        until: Option<i64>,
+    ) -> Value {
+        let seeded = match until {
+            Some(until) => serde_json::json!({ SUDO_SESSION_KEY: until }),
+            None => serde_json::json!({ "unrelated": true }),
+        };
+        let req = TestRequest::post().uri("/seed").set_json(seeded).to_request();
+        let cookie = session_cookie(&call_service(app, req).await);
+
+        let req = TestRequest::get().uri("/sudo").cookie(cookie).to_request();
+        call_and_read_body_json(app, req).await
+    }
+
+    #[actix_web::test]
+    async fn sudo_lasts_for_its_window_then_is_dropped() {
+        let app = test_app().await;
+        let now = Utc::now().timestamp();
+
+        let state = sudo_state(&app, Some(now + 60)).await;
+        assert_eq!(state["sudo"], true);
+        assert_eq!(state["until"], now + 60);
+
+        let state = sudo_state(&app, Some(now - 1)).await;
+        assert_eq!(state["sudo"], false);
+        assert_eq!(state["until"], Value::Null);
+
+        assert_eq!(sudo_state(&app, None).await["sudo"], false);
+    }
+
+    #[test]
+    fn remember_me_policies_set_cookie_and_lifetime() {
+        let config = Config::for_tests();
+
+        let short = RememberMePolicy::from_form(None);
+        assert_eq!(short, RememberMePolicy::from_form(Some(false)));
+        assert_eq!(short, RememberMePolicy::Short);
+        assert_eq!(short.max_lifetime_secs(&config), 720 * 60);
+        assert_eq!(short.cookie_max_age(&config), None);
+
+        let long = RememberMePolicy::from_form(Some(true));
+        assert_eq!(long, RememberMePolicy::Long);
+        assert_eq!(long.max_lifetime_secs(&config), 30 * 24 * 60 * 60);
+        assert_eq!(long.cookie_max_age(&config), Some(time::Duration::days(30)));
+    }
+
+    #[actix_web::test]
+    async fn verified_user_requires_a_verified_primary_when_configured() {
+        let config = Config { require_verified_email: true, ..Config::for_tests() };
+        let mut client = client(config).await;
+        client.sign_up("ada@example.com", "ada").await;
+        client.sudo().await;
+        let rename = [("username", "countess")];
+
+        let reply = client.submit("/profile", "/profile/username", &rename).await;
+        assert_eq!(reply.location(), "/profile?msg=email_unverified");
+        assert_eq!(client.get("/dashboard").await.status, StatusCode::OK);
+
+        // Read fresh on each request, so verifying takes effect at once
+        let ada = client.db.get_user_by_username("ada").await.unwrap().unwrap();
+        client.db.verify_email(&ada.id, "ada@example.com").await.unwrap();
+        let reply = client.submit("/profile", "/profile/username", &rename).await;
+        assert_eq!(reply.location(), "/profile?msg=username_changed");
+    }
+
+    #[actix_web::test]
+    async fn verified_user_lets_anyone_through_when_not_required() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+        client.sudo().await;
+
+        let reply = client.submit("/profile", "/profile/username", &[("username", "countess")]).await;
+        assert_eq!(reply.location(), "/profile?msg=username_changed");
+    }
+}
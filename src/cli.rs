@@ -0,0 +1,143 @@
+use anyhow::{bail, Result};
+use chrono::{Duration, Utc};
+use crate::config::Config;
+use crate::database::Database;
+use crate::features;
+
+const USAGE: &str = "usage:
+  rust-web-auth                              start the web server
+  rust-web-auth case-conflicts               list emails/usernames that differ only by case
+  rust-web-auth merge-accounts KEEP_ID DROP_ID
+                                             fold DROP_ID into KEEP_ID and deactivate it
+  rust-web-auth deactivate-inactive          deactivate accounts idle for INACTIVE_ACCOUNT_DAYS
+  rust-web-auth inactive-accounts            list deactivated accounts
+  rust-web-auth reactivate-account ID        reactivate a deactivated account
+  rust-web-auth emails ID                    list an account's email addresses
+  rust-web-auth add-email ID ADDRESS         add an unverified secondary address
+  rust-web-auth verify-email ID ADDRESS      mark an address as verified
+  rust-web-auth set-primary-email ID ADDRESS make a verified address the primary
+  rust-web-auth remove-email ID ADDRESS      remove a secondary address
+  rust-web-auth features                     list feature flags
+  rust-web-auth feature NAME on|off          turn a feature flag on or off";
+
+/// Maintenance subcommands, run instead of the server when arguments are
+/// given.
+pub async fn run(args: &[String], config: &Config, db: &Database) -> Result<()> {
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["case-conflicts"] => {
+            let conflicts = db.find_case_conflicts().await?;
+            if conflicts.is_empty() {
+                println!("No case conflicts found.");
+            }
+
+            let ids: Vec<String> = conflicts
+                .iter()
+                .flat_map(|conflict| conflict.accounts.iter().map(|(id, _)| id.clone()))
+                .collect();
+            let users = db.get_users_by_ids(&ids).await?;
+
+            for conflict in conflicts {
+                println!("{} conflict:", conflict.field);
+                for (id, value) in conflict.accounts {
+                    match users.get(&id) {
+                        Some(user) => println!(
+                            "  {}  {}  (@{}, joined {})",
+                            id,
+                            value,
+                            user.username,
+                            user.created_at.format("%Y-%m-%d")
+                        ),
+                        None => println!("  {}  {}", id, value),
+                    }
+                }
+            }
+            Ok(())
+        }
+        ["merge-accounts", keep_id, drop_id] => {
+            db.merge_accounts(keep_id, drop_id).await?;
+            println!("Merged {} into {}; {} is now deactivated.", drop_id, keep_id, drop_id);
+            Ok(())
+        }
+        ["deactivate-inactive"] => {
+            let Some(days) = config.inactive_account_days else {
+                bail!("INACTIVE_ACCOUNT_DAYS is not set; no accounts were deactivated");
+            };
+            let count = db.deactivate_inactive(Utc::now() - Duration::days(days)).await?;
+            println!("Deactivated {} account(s) with no login in the last {} days.", count, days);
+            Ok(())
+        }
+        ["inactive-accounts"] => {
+            let accounts = db.list_inactive().await?;
+            if accounts.is_empty() {
+                println!("No deactivated accounts.");
+            }
+            for (id, email, last_seen) in accounts {
+                println!("  {}  {}  (last seen {})", id, email, last_seen.format("%Y-%m-%d"));
+            }
+            Ok(())
+        }
+        ["reactivate-account", id] => {
+            if !db.reactivate_user(id).await? {
+                bail!("no deactivated account with id {}", id);
+            }
+            println!("Reactivated {}.", id);
+            Ok(())
+        }
+        ["emails", id] => {
+            for email in db.list_emails(id).await? {
+                let primary = if email.is_primary { "  primary" } else { "" };
+                let verified = if email.verified { "verified" } else { "unverified" };
+                println!("  {}  {}{}", email.address, verified, primary);
+            }
+            Ok(())
+        }
+        ["add-email", id, address] => {
+            if db.get_user_by_id(id).await?.is_none() {
+                bail!("no account with id {}", id);
+            }
+            if !db.add_email(id, address).await? {
+                bail!("{} is already in use", address);
+            }
+            println!("Added {} to {} (unverified).", address, id);
+            Ok(())
+        }
+        ["verify-email", id, address] => {
+            if !db.verify_email(id, address).await? {
+                bail!("{} has no address {}", id, address);
+            }
+            println!("Verified {}.", address);
+            Ok(())
+        }
+        ["set-primary-email", id, address] => {
+            if !db.set_primary_email(id, address).await? {
+                bail!("{} has no verified address {}", id, address);
+            }
+            println!("{} is now the primary address of {}.", address, id);
+            Ok(())
+        }
+        ["remove-email", id, address] => {
+            if !db.remove_email(id, address).await? {
+                bail!("{} has no secondary address {}", id, address);
+            }
+            println!("Removed {}.", address);
+            Ok(())
+        }
+        ["features"] => {
+            let flags = db.feature_flags().await?;
+            for (name, _) in features::FLAGS {
+                let state = if flags.get(*name).copied().unwrap_or(false) { "on" } else { "off" };
+                println!("  {:<20} {}", name, state);
+            }
+            Ok(())
+        }
+        ["feature", name, state @ ("on" | "off")] => {
+            if !features::is_known(name) {
+                bail!("unknown feature flag {}", name);
+            }
+            db.set_feature_flag(name, *state == "on").await?;
+            println!("Turned {} {}. Running servers pick this up within {} seconds.", name, state, features::CACHE_TTL.as_secs());
+            Ok(())
+        }
+        _ => bail!("{}", USAGE),
+    }
+}
@@ -0,0 +1,69 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_TYPE};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use crate::config::Config;
+
+/// Keeps `Compress` off responses it shouldn't touch: HTML pages, and
+/// anything smaller than `COMPRESS_MIN_BYTES`, where compressing costs more
+/// CPU than the bytes it saves. Pages mix secrets (the CSRF token) with
+/// reflected input, so compressing them would open them to BREACH-style
+/// length attacks; static assets and JSON are still compressed. `Compress`
+/// leaves a response alone once it carries a `Content-Encoding`, so skipped
+/// ones are marked `identity`. Images and video are already skipped by
+/// `Compress` itself. Streamed responses of unknown size are compressed.
+pub async fn skip_compression(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let min_bytes = req
+        .app_data::<web::Data<Config>>()
+        .map_or(0, |config| config.compress_min_bytes);
+
+    let mut response = next.call(req).await?;
+
+    let small = matches!(response.response().body().size(), BodySize::Sized(len) if len < min_bytes);
+    let html = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/html"));
+    if (small || html) && !response.headers().contains_key(CONTENT_ENCODING) {
+        response
+            .headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("identity"));
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+    use actix_web::test::TestRequest;
+    use crate::config::Config;
+    use crate::test_support::client;
+
+    #[actix_web::test]
+    async fn only_large_non_html_responses_are_gzipped() {
+        let mut client = client(Config::for_tests()).await;
+        for _ in 0..50 {
+            client.db.record_bench_run("int_mix", 1000, 0.5, 2000.0).await.unwrap();
+        }
+
+        for (uri, gzipped) in [("/health", false), ("/api/bench/history?limit=50", true), ("/register", false)] {
+            let reply = client
+                .send(TestRequest::get().uri(uri).insert_header((ACCEPT_ENCODING, "gzip")))
+                .await;
+            let encoding = reply.headers.get(CONTENT_ENCODING).map(|value| value.to_str().unwrap().to_string());
+            assert_eq!(encoding.as_deref() == Some("gzip"), gzipped, "{uri}: {encoding:?}");
+            // gzip streams start with the magic byte 0x1f
+            assert_eq!(reply.body.starts_with('\u{1f}'), gzipped, "{uri}");
+        }
+
+        // Pages stay uncompressed for their type, not their size
+        let page = client.get("/register").await;
+        assert!(page.body.len() as u64 > Config::for_tests().compress_min_bytes);
+    }
+}
@@ -0,0 +1,106 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Paths that are never shed, so load balancers can still probe an
+/// overloaded instance.
+const EXEMPT_PATHS: &[&str] = &["/health"];
+
+/// Seconds clients are told to wait before retrying a shed request.
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Shared permit pool for `limit_concurrency`. Create it once, outside the
+/// `HttpServer::new` closure, so the cap applies across all workers.
+#[derive(Clone)]
+pub struct ConcurrencyLimit(Arc<Semaphore>);
+
+impl ConcurrencyLimit {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self(Arc::new(Semaphore::new(max_in_flight)))
+    }
+}
+
+/// Load shedding: caps the number of requests being handled at once and
+/// answers the rest with 503 and `Retry-After` instead of queueing them.
+/// Reads the `ConcurrencyLimit` from app data; without one it passes
+/// everything through.
+pub async fn limit_concurrency(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let path = req.path().trim_end_matches('/');
+    let limit = req.app_data::<web::Data<ConcurrencyLimit>>().cloned();
+
+    let Some(limit) = limit.filter(|_| !EXEMPT_PATHS.contains(&path)) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    // Held until the handler has produced its response
+    let Ok(_permit) = limit.0.try_acquire() else {
+        log::warn!("Shedding request to {}: concurrency limit reached", req.path());
+        let response = HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", RETRY_AFTER_SECS.to_string()))
+            .body("Server is busy, please retry shortly");
+        return Ok(req.into_response(response).map_into_right_body());
+    };
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+    use tokio::sync::watch;
+
+    /// Doesn't answer until the test opens the gate, holding its permit.
+    async fn slow(gate: web::Data<watch::Receiver<bool>>) -> HttpResponse {
+        let mut gate = gate.get_ref().clone();
+        gate.wait_for(|open| *open).await.unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    async fn fast() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn requests_beyond_the_cap_are_shed() {
+        let (open_gate, gate) = watch::channel(false);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(ConcurrencyLimit::new(2)))
+                .app_data(web::Data::new(gate))
+                .wrap(from_fn(limit_concurrency))
+                .route("/slow", web::get().to(slow))
+                .route("/fast", web::get().to(fast))
+                .route("/health", web::get().to(fast)),
+        )
+        .await;
+
+        let get = |uri: &str| call_service(&app, TestRequest::get().uri(uri).to_request());
+        let (first, second, (shed, health)) = futures_util::join!(get("/slow"), get("/slow"), async {
+            // Both slow requests now hold a permit
+            tokio::task::yield_now().await;
+            let shed = get("/fast").await;
+            let health = get("/health").await;
+            open_gate.send(true).unwrap();
+            (shed, health)
+        });
+
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.headers().get("retry-after").unwrap(), "1");
+        assert_eq!(health.status(), StatusCode::OK);
+
+        // Permits are returned once the slow requests finish
+        assert_eq!(get("/fast").await.status(), StatusCode::OK);
+    }
+}
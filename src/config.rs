@@ -0,0 +1,468 @@
+use actix_web::cookie::{Key, SameSite};
+use actix_web::middleware::TrailingSlash;
+use anyhow::{bail, Result};
+use std::env;
+use std::fs;
+use std::path::Path;
+use crate::redirect::is_local_path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppEnv {
+    Development,
+    Production,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Deployment environment (`APP_ENV`: `development` (default) or
+    /// `production`). Production refuses to start with insecure settings.
+    pub app_env: AppEnv,
+    /// Secret the session cookie key is derived from (`SESSION_SECRET`, at
+    /// least 32 bytes). When unset a random key is generated at startup, so
+    /// every restart logs all users out.
+    pub session_secret: Option<String>,
+    /// Application-wide secret mixed into every password before bcrypt
+    /// (`PASSWORD_PEPPER`). Unset keeps plain bcrypt. Hashes only verify with
+    /// the pepper they were created with: setting, changing or removing it
+    /// locks out every existing account until those users reset their
+    /// passwords, so choose it before the first user registers.
+    pub password_pepper: Option<String>,
+    /// SQLite database to use (`DATABASE_URL`, default `sqlite://users.db`).
+    /// Missing parent directories are created at startup.
+    pub database_url: String,
+    /// Pragmas run on every new SQLite connection, as `(name, value)` pairs.
+    /// Defaults to WAL journaling, `synchronous=NORMAL`, foreign keys on and
+    /// a 5 second busy timeout; `SQLITE_PRAGMAS` (e.g.
+    /// `synchronous=FULL,cache_size=-20000`) overrides or adds to them.
+    pub sqlite_pragmas: Vec<(String, String)>,
+    /// Database queries taking longer than this many milliseconds are logged
+    /// as warnings (`SLOW_QUERY_MS`, default 100).
+    pub slow_query_ms: u64,
+    /// Hard cap on how long a login session stays valid, regardless of
+    /// activity (`SESSION_MAX_LIFETIME_MINUTES`, default 720 = 12 hours).
+    pub session_max_lifetime_minutes: i64,
+    /// How long a "remember me" login lasts, both as the session cookie's
+    /// `Max-Age` and as its absolute lifetime cap (`REMEMBER_ME_DAYS`,
+    /// default 30).
+    pub remember_me_days: i64,
+    /// Minimum number of days between username changes
+    /// (`USERNAME_CHANGE_COOLDOWN_DAYS`, default 30).
+    pub username_change_cooldown_days: i64,
+    /// How request paths are normalized before routing (`TRAILING_SLASH`):
+    /// `trim` (default) strips trailing slashes so `/login/` matches `/login`,
+    /// `merge` only collapses repeated slashes.
+    pub trailing_slash: TrailingSlash,
+    /// SameSite attribute of the session cookie (`SESSION_SAME_SITE`:
+    /// `lax` (default), `strict` or `none`). `strict` also drops the session on
+    /// cross-site navigations, so users following a link from another site
+    /// arrive logged out. `none` always forces the Secure flag.
+    pub session_same_site: SameSite,
+    /// Whether the session cookie is marked Secure (`SESSION_COOKIE_SECURE`,
+    /// default true).
+    pub cookie_secure: bool,
+    /// Query parameters whose values are replaced with `[REDACTED]` in the
+    /// access log (`LOG_REDACT_PARAMS`, comma-separated, default
+    /// `token,code,password`).
+    pub log_redact_params: Vec<String>,
+    /// Where users land after logging in when no `next` path was requested
+    /// (`DEFAULT_LOGIN_REDIRECT`, default `/dashboard`). Must be a local path.
+    pub default_login_redirect: String,
+    /// Directory served under `/assets` (`STATIC_DIR`); `None` when unset,
+    /// in which case `static/assets` is served if it exists. Use
+    /// `static_dir()` for the directory actually served.
+    pub static_dir: Option<String>,
+    /// Gravatar default-image style used for users without an avatar, e.g.
+    /// `identicon` (`GRAVATAR_DEFAULT`). Gravatar is only used when
+    /// `GRAVATAR_ENABLED=true`; otherwise this is `None`.
+    pub gravatar_style: Option<String>,
+    /// Maximum number of requests handled at once; beyond it requests get a
+    /// 503 with `Retry-After` (`MAX_CONCURRENT_REQUESTS`, default 256).
+    pub max_concurrent_requests: usize,
+    /// Requests running more database queries than this are logged as
+    /// warnings (`QUERY_COUNT_WARN`, default 10).
+    pub query_count_warn: usize,
+    /// Enables developer-only routes such as `/dev/render/{template}`
+    /// (`DEV_TOOLS=1`). Always off in production.
+    pub dev_tools: bool,
+    /// Minifies rendered HTML pages (`MINIFY_HTML=1`). Off by default so
+    /// template output stays readable while debugging.
+    pub minify_html: bool,
+    /// Accounts with no login for this many days (counting from sign-up for
+    /// accounts that never logged in) are deactivated by the
+    /// `deactivate-inactive` command (`INACTIVE_ACCOUNT_DAYS`). Unset means
+    /// accounts never expire.
+    pub inactive_account_days: Option<i64>,
+    /// Delay before answering a failed login, doubled for each further
+    /// failure from the same email and IP (`LOGIN_TARPIT_MS`, default 0 =
+    /// off).
+    pub login_tarpit_ms: u64,
+    /// Longest tarpit delay (`LOGIN_TARPIT_MAX_MS`, default 10000).
+    pub login_tarpit_max_ms: u64,
+    /// Lets users log in with any verified secondary email, not just their
+    /// primary one (`LOGIN_WITH_VERIFIED_EMAILS=1`).
+    pub login_with_verified_emails: bool,
+    /// Responses smaller than this many bytes are sent uncompressed
+    /// (`COMPRESS_MIN_BYTES`, default 1024).
+    pub compress_min_bytes: u64,
+    /// Blocks sensitive account changes, such as a new password or
+    /// username, until the primary email is verified
+    /// (`REQUIRE_VERIFIED_EMAIL=1`). There is no self-service verification
+    /// yet: new accounts stay blocked until an operator runs
+    /// `rust-web-auth verify-email ID ADDRESS`. Accounts from before the emails
+    /// table count as verified.
+    pub require_verified_email: bool,
+    /// Contact form messages accepted per client IP per hour
+    /// (`CONTACT_RATE_LIMIT`, default 5, 0 = unlimited).
+    pub contact_rate_limit: u32,
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let app_env = match env::var("APP_ENV").unwrap_or_default().trim().to_lowercase().as_str() {
+            "" | "development" | "dev" => AppEnv::Development,
+            "production" | "prod" => AppEnv::Production,
+            other => bail!("APP_ENV must be development or production (got {:?})", other),
+        };
+
+        let session_secret = env::var("SESSION_SECRET").ok().filter(|secret| !secret.is_empty());
+        if session_secret.as_ref().is_some_and(|secret| secret.len() < 32) {
+            bail!("SESSION_SECRET must be at least 32 bytes long");
+        }
+
+        let session_same_site = same_site_from_env()?;
+        let sqlite_pragmas = sqlite_pragmas_from_env()?;
+
+        let default_login_redirect = env::var("DEFAULT_LOGIN_REDIRECT")
+            .map(|value| value.trim().to_string())
+            .unwrap_or_else(|_| "/dashboard".to_string());
+        if !is_local_path(&default_login_redirect) {
+            bail!("DEFAULT_LOGIN_REDIRECT must be a local path such as /dashboard (got {:?})", default_login_redirect);
+        }
+
+        Ok(Self {
+            app_env,
+            session_secret,
+            password_pepper: env::var("PASSWORD_PEPPER").ok().filter(|pepper| !pepper.is_empty()),
+            database_url: env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://users.db".to_string()),
+            sqlite_pragmas,
+            slow_query_ms: env_or("SLOW_QUERY_MS", 100),
+            session_max_lifetime_minutes: env_or("SESSION_MAX_LIFETIME_MINUTES", 720),
+            remember_me_days: env_or("REMEMBER_ME_DAYS", 30),
+            username_change_cooldown_days: env_or("USERNAME_CHANGE_COOLDOWN_DAYS", 30),
+            trailing_slash: trailing_slash_from_env(),
+            session_same_site,
+            // Browsers reject SameSite=None cookies that aren't Secure
+            cookie_secure: session_same_site == SameSite::None || env_or("SESSION_COOKIE_SECURE", true),
+            log_redact_params: env_list("LOG_REDACT_PARAMS", &["token", "code", "password"]),
+            default_login_redirect,
+            static_dir: env::var("STATIC_DIR").ok().filter(|dir| !dir.is_empty()),
+            gravatar_style: env_or("GRAVATAR_ENABLED", false)
+                .then(|| env::var("GRAVATAR_DEFAULT").unwrap_or_else(|_| "identicon".to_string())),
+            max_concurrent_requests: env_or("MAX_CONCURRENT_REQUESTS", 256),
+            query_count_warn: env_or("QUERY_COUNT_WARN", 10),
+            dev_tools: app_env != AppEnv::Production && env_flag("DEV_TOOLS"),
+            minify_html: env_flag("MINIFY_HTML"),
+            inactive_account_days: env::var("INACTIVE_ACCOUNT_DAYS").ok().and_then(|days| days.trim().parse().ok()),
+            login_tarpit_ms: env_or("LOGIN_TARPIT_MS", 0),
+            login_tarpit_max_ms: env_or("LOGIN_TARPIT_MAX_MS", 10_000),
+            login_with_verified_emails: env_flag("LOGIN_WITH_VERIFIED_EMAILS"),
+            compress_min_bytes: env_or("COMPRESS_MIN_BYTES", 1024),
+            require_verified_email: env_flag("REQUIRE_VERIFIED_EMAIL"),
+            contact_rate_limit: env_or("CONTACT_RATE_LIMIT", 5),
+        })
+    }
+
+    /// The defaults `from_env` would produce with no variables set, but with
+    /// a fresh in-memory database, so tests never depend on the environment.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Self {
+            app_env: AppEnv::Development,
+            session_secret: None,
+            password_pepper: None,
+            database_url: "sqlite::memory:".to_string(),
+            sqlite_pragmas: DEFAULT_SQLITE_PRAGMAS
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            slow_query_ms: 100,
+            session_max_lifetime_minutes: 720,
+            remember_me_days: 30,
+            username_change_cooldown_days: 30,
+            trailing_slash: TrailingSlash::Trim,
+            session_same_site: SameSite::Lax,
+            cookie_secure: true,
+            log_redact_params: vec!["token".to_string(), "code".to_string(), "password".to_string()],
+            default_login_redirect: "/dashboard".to_string(),
+            static_dir: None,
+            gravatar_style: None,
+            max_concurrent_requests: 256,
+            query_count_warn: 10,
+            dev_tools: false,
+            minify_html: false,
+            inactive_account_days: None,
+            login_tarpit_ms: 0,
+            login_tarpit_max_ms: 10_000,
+            login_with_verified_emails: false,
+            compress_min_bytes: 1024,
+            require_verified_email: false,
+            contact_rate_limit: 5,
+        }
+    }
+
+    /// Key used to sign and encrypt the session cookie.
+    pub fn session_key(&self) -> Key {
+        match &self.session_secret {
+            Some(secret) => Key::derive_from(secret.as_bytes()),
+            None => Key::generate(),
+        }
+    }
+
+    /// Checks for settings that are unsafe outside local development. In
+    /// production any problem is an error listing all of them; in development
+    /// they are logged as warnings.
+    pub fn validate_for_env(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if !self.cookie_secure {
+            problems.push("SESSION_COOKIE_SECURE is off, so session cookies are sent over plain HTTP");
+        }
+        if self.session_secret.is_none() {
+            problems.push("SESSION_SECRET is not set, so a random session key is used and sessions reset on every restart");
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        match self.app_env {
+            AppEnv::Production => bail!(
+                "refusing to start in production with insecure settings:\n  - {}",
+                problems.join("\n  - ")
+            ),
+            AppEnv::Development => {
+                for problem in problems {
+                    log::warn!("{}", problem);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The directory served under `/assets`.
+    pub fn static_dir(&self) -> &str {
+        self.static_dir.as_deref().unwrap_or(DEFAULT_STATIC_DIR)
+    }
+
+    /// Checks that the static assets directory exists and has files in it,
+    /// since a missing one only shows up as unstyled pages and 404s under
+    /// `/assets`. Always a warning naming the resolved path; fatal only in
+    /// production with an explicitly set `STATIC_DIR`, since the default
+    /// `static/assets` may legitimately be absent.
+    pub fn check_static_dir(&self) -> Result<()> {
+        let dir = Path::new(self.static_dir());
+        let resolved = dir.canonicalize().unwrap_or_else(|_| env::current_dir().unwrap_or_default().join(dir));
+
+        let problem = match fs::read_dir(dir) {
+            Err(_) => Some(format!("static assets directory {} does not exist", resolved.display())),
+            Ok(mut entries) => entries
+                .next()
+                .is_none()
+                .then(|| format!("static assets directory {} is empty", resolved.display())),
+        };
+
+        match problem {
+            None => Ok(()),
+            Some(problem) if self.static_dir.is_some() && self.app_env == AppEnv::Production => {
+                bail!("{} (set STATIC_DIR to override)", problem)
+            }
+            Some(problem) => {
+                log::warn!("{}; /assets will return 404s (set STATIC_DIR to override)", problem);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn same_site_from_env() -> Result<SameSite> {
+    let value = env::var("SESSION_SAME_SITE").unwrap_or_default();
+    match value.trim().to_lowercase().as_str() {
+        "" | "lax" => Ok(SameSite::Lax),
+        "strict" => Ok(SameSite::Strict),
+        "none" => Ok(SameSite::None),
+        other => bail!("SESSION_SAME_SITE must be one of strict, lax or none (got {:?})", other),
+    }
+}
+
+/// Served under `/assets` when `STATIC_DIR` is unset.
+const DEFAULT_STATIC_DIR: &str = "static/assets";
+
+const DEFAULT_SQLITE_PRAGMAS: &[(&str, &str)] = &[
+    ("journal_mode", "WAL"),
+    ("synchronous", "NORMAL"),
+    ("foreign_keys", "ON"),
+    ("busy_timeout", "5000"),
+];
+
+fn sqlite_pragmas_from_env() -> Result<Vec<(String, String)>> {
+    sqlite_pragmas(&env_list("SQLITE_PRAGMAS", &[]))
+}
+
+/// The default pragmas with `overrides` (`name=value` entries) applied on
+/// top: a known name takes the new value, an unknown one is appended.
+fn sqlite_pragmas(overrides: &[String]) -> Result<Vec<(String, String)>> {
+    let mut pragmas: Vec<(String, String)> = DEFAULT_SQLITE_PRAGMAS
+        .iter()
+        .map(|&(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+
+    for item in overrides {
+        let Some((name, value)) = item.split_once('=') else {
+            bail!("SQLITE_PRAGMAS entries must look like name=value (got {:?})", item);
+        };
+        let (name, value) = (name.trim().to_lowercase(), value.trim().to_string());
+
+        // Both end up in a PRAGMA statement, which can't take bound parameters
+        let is_plain = |s: &str| {
+            !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if !is_plain(&name) || !is_plain(&value) {
+            bail!("SQLITE_PRAGMAS entry {:?} may only use letters, digits, _ and -", item);
+        }
+
+        match pragmas.iter_mut().find(|(existing, _)| *existing == name) {
+            Some(pragma) => pragma.1 = value,
+            None => pragmas.push((name, value)),
+        }
+    }
+
+    Ok(pragmas)
+}
+
+fn trailing_slash_from_env() -> TrailingSlash {
+    match env::var("TRAILING_SLASH").unwrap_or_default().trim().to_lowercase().as_str() {
+        "merge" => TrailingSlash::MergeOnly,
+        _ => TrailingSlash::Trim,
+    }
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+/// True for `1`, `true`, `yes` or `on` (any case).
+fn env_flag(key: &str) -> bool {
+    matches!(
+        env::var(key).unwrap_or_default().trim().to_lowercase().as_str(),
+        "1" | "true" | "yes" | "on"
+    )
+}
+
+fn env_list(key: &str, default: &[&str]) -> Vec<String> {
+    match env::var(key) {
+        Ok(value) => value
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        Err(_) => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{capture_logs, logged};
+
+    fn production() -> Config {
+        Config {
+            app_env: AppEnv::Production,
+            session_secret: Some("x".repeat(64)),
+            ..Config::for_tests()
+        }
+    }
+
+    #[test]
+    fn secure_production_config_passes() {
+        assert!(production().validate_for_env().is_ok());
+    }
+
+    #[test]
+    fn insecure_production_config_fails_listing_every_problem() {
+        let config = Config { cookie_secure: false, session_secret: None, ..production() };
+
+        let error = config.validate_for_env().unwrap_err().to_string();
+        assert!(error.contains("SESSION_COOKIE_SECURE is off"), "{error}");
+        assert!(error.contains("SESSION_SECRET is not set"), "{error}");
+
+        let config = Config { cookie_secure: false, ..production() };
+        assert!(config.validate_for_env().is_err());
+    }
+
+    #[test]
+    fn insecure_development_config_only_warns() {
+        let config = Config { cookie_secure: false, ..Config::for_tests() };
+        assert!(config.validate_for_env().is_ok());
+    }
+
+    fn pragmas(overrides: &[&str]) -> Result<Vec<(String, String)>> {
+        sqlite_pragmas(&overrides.iter().map(|item| item.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn sqlite_pragmas_default_to_wal_and_foreign_keys() {
+        let defaults = pragmas(&[]).unwrap();
+        assert!(defaults.contains(&("journal_mode".to_string(), "WAL".to_string())));
+        assert!(defaults.contains(&("foreign_keys".to_string(), "ON".to_string())));
+        assert!(defaults.contains(&("busy_timeout".to_string(), "5000".to_string())));
+    }
+
+    #[test]
+    fn sqlite_pragma_overrides_replace_or_extend_the_defaults() {
+        let pragmas = pragmas(&["BUSY_TIMEOUT = 10000", "cache_size=-20000"]).unwrap();
+        assert_eq!(pragmas.len(), DEFAULT_SQLITE_PRAGMAS.len() + 1);
+        assert!(pragmas.contains(&("busy_timeout".to_string(), "10000".to_string())));
+        assert_eq!(pragmas.last().unwrap(), &("cache_size".to_string(), "-20000".to_string()));
+    }
+
+    #[test]
+    fn sqlite_pragmas_refuse_anything_but_plain_words() {
+        for item in ["foreign_keys", "foreign_keys=", "journal_mode=WAL; DROP TABLE users", "x'1'=1"] {
+            assert!(pragmas(&[item]).is_err(), "{item}");
+        }
+    }
+
+    #[test]
+    fn missing_default_static_dir_only_warns() {
+        capture_logs();
+        let config = production();
+        assert_eq!(config.static_dir(), "static/assets");
+        assert!(config.check_static_dir().is_ok());
+
+        let populated = fs::read_dir(DEFAULT_STATIC_DIR).is_ok_and(|mut entries| entries.next().is_some());
+        let warned = logged("static assets directory")
+            .iter()
+            .any(|line| line.starts_with("WARN") && line.contains("static/assets"));
+        assert!(populated || warned);
+    }
+
+    #[test]
+    fn configured_static_dir_must_exist_in_production() {
+        let dir = std::env::temp_dir().join(format!("static-test-{}", uuid::Uuid::new_v4().simple()));
+        let config = Config {
+            static_dir: Some(dir.to_string_lossy().into_owned()),
+            ..production()
+        };
+        assert!(config.check_static_dir().unwrap_err().to_string().contains("does not exist"));
+
+        fs::create_dir_all(&dir).unwrap();
+        assert!(config.check_static_dir().unwrap_err().to_string().contains("is empty"));
+
+        fs::write(dir.join("app.css"), "body {}").unwrap();
+        assert!(config.check_static_dir().is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
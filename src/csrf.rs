@@ -0,0 +1,273 @@
+use actix_session::Session;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, HttpResponse, ResponseError};
+use tera::{Context, Tera};
+use uuid::Uuid;
+use crate::api::ApiError;
+use crate::redirect;
+
+const CSRF_SESSION_KEY: &str = "csrf_token";
+
+/// Returns the session's CSRF token, creating one on first use. The token is
+/// stable for the life of the session so open forms stay valid across renders.
+pub fn csrf_token(session: &Session) -> Result<String, Error> {
+    if let Ok(Some(token)) = session.get::<String>(CSRF_SESSION_KEY) {
+        return Ok(token);
+    }
+
+    let token = Uuid::new_v4().simple().to_string();
+    session.insert(CSRF_SESSION_KEY, &token)
+        .map_err(|e| actix_web::error::ErrorInternalServerError(format!("Session storage error: {}", e)))?;
+
+    Ok(token)
+}
+
+/// Drops the session's CSRF token so the next form render issues a new one.
+/// Forms already open in other tabs stop validating.
+pub fn rotate_csrf_token(session: &Session) {
+    session.remove(CSRF_SESSION_KEY);
+}
+
+/// Adds `csrf_token` and a ready-made hidden `csrf_field` input to a form
+/// page's context. Templates render the field with `{{ csrf_field | safe }}`.
+pub fn with_csrf(ctx: &mut Context, session: &Session) -> Result<(), Error> {
+    let token = csrf_token(session)?;
+    ctx.insert(
+        "csrf_field",
+        &format!(r#"<input type="hidden" name="csrf_token" value="{}">"#, token),
+    );
+    ctx.insert("csrf_token", &token);
+    Ok(())
+}
+
+/// A submitted CSRF token that doesn't match the session's. On its own it is
+/// a bare 403; `csrf_failure_page` turns it into a page or JSON error.
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid CSRF token")]
+pub struct CsrfError;
+
+impl ResponseError for CsrfError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::FORBIDDEN
+    }
+}
+
+/// Rejects a form submission whose token doesn't match the session's.
+pub fn verify_csrf(session: &Session, submitted: &str) -> Result<(), Error> {
+    let expected = session.get::<String>(CSRF_SESSION_KEY).ok().flatten();
+
+    match expected {
+        Some(expected) if constant_time_eq(expected.as_bytes(), submitted.as_bytes()) => Ok(()),
+        _ => Err(CsrfError.into()),
+    }
+}
+
+/// Header carrying the CSRF token on requests without a form body. Pages
+/// expose the token to scripts as `<meta name="csrf-token">`.
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// `verify_csrf` for the token in the `X-CSRF-Token` header, for API
+/// endpoints that act on the session cookie.
+pub fn verify_csrf_header(req: &HttpRequest, session: &Session) -> Result<(), Error> {
+    let submitted = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    verify_csrf(session, submitted)
+}
+
+/// Replaces the bare 403 of a `CsrfError` with something the client can act
+/// on: `errors/csrf.html.tera`, linking back to the form, for browsers, and
+/// a `csrf_failed` `ApiError` for `/api` routes and JSON clients.
+pub async fn csrf_failure_page(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let res = next.call(req).await?;
+
+    let failed = res
+        .response()
+        .error()
+        .is_some_and(|error| error.as_error::<CsrfError>().is_some());
+    if !failed {
+        return Ok(res.map_into_left_body());
+    }
+
+    let (req, _) = res.into_parts();
+    let response = if wants_json(&req) {
+        ApiError::new(
+            StatusCode::FORBIDDEN,
+            "csrf_failed",
+            "Invalid or missing CSRF token; fetch a fresh one and try again",
+        )
+        .error_response()
+    } else {
+        let mut ctx = Context::new();
+        ctx.insert("brand_name", "Rust Web AI");
+        ctx.insert("back_url", &back_url(&req));
+
+        let body = match req.app_data::<web::Data<Tera>>() {
+            Some(tmpl) => tmpl
+                .render("errors/csrf.html.tera", &ctx)
+                .unwrap_or_else(|e| format!("Template error: {e}")),
+            None => "Your session expired. Please go back, reload the page and try again.".to_string(),
+        };
+        HttpResponse::Forbidden()
+            .content_type("text/html; charset=utf-8")
+            .body(body)
+    };
+
+    Ok(ServiceResponse::new(req, response).map_into_right_body())
+}
+
+fn wants_json(req: &HttpRequest) -> bool {
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    req.path().starts_with("/api/") || (accept.contains("application/json") && !accept.contains("text/html"))
+}
+
+/// The page the failed form was on, from a same-origin `Referer`, or `/`.
+fn back_url(req: &HttpRequest) -> String {
+    let host = req.connection_info().host().to_string();
+    req.headers()
+        .get(header::REFERER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|referer| referer.split_once("://"))
+        .and_then(|(_, rest)| rest.strip_prefix(host.as_str()))
+        .filter(|path| redirect::is_local_path(path))
+        .map_or_else(|| "/".to_string(), str::to_string)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::Cookie;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use serde_json::Value;
+    use crate::config::Config;
+    use crate::test_support::{client, session_middleware};
+
+    async fn issue(session: Session) -> Result<HttpResponse, Error> {
+        Ok(HttpResponse::Ok().body(csrf_token(&session)?))
+    }
+
+    async fn check(session: Session, body: String) -> Result<HttpResponse, Error> {
+        verify_csrf(&session, &body)?;
+        Ok(HttpResponse::Ok().finish())
+    }
+
+    #[test]
+    fn constant_time_eq_compares_contents_and_length() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[actix_web::test]
+    async fn verify_csrf_accepts_only_the_session_token() {
+        let app = init_service(
+            App::new()
+                .wrap(session_middleware())
+                .route("/token", web::get().to(issue))
+                .route("/check", web::post().to(check)),
+        )
+        .await;
+
+        // No token issued yet, so nothing matches, not even an empty one
+        let req = TestRequest::post().uri("/check").set_payload("").to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let res = call_service(&app, TestRequest::get().uri("/token").to_request()).await;
+        let cookie: Cookie = res.response().cookies().next().unwrap().into_owned();
+        let token = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+        assert_eq!(token.len(), 32);
+
+        for (submitted, expected) in [
+            (token.as_str(), StatusCode::OK),
+            ("", StatusCode::FORBIDDEN),
+            (&token[..31], StatusCode::FORBIDDEN),
+            ("0123456789abcdef0123456789abcdef", StatusCode::FORBIDDEN),
+        ] {
+            let req = TestRequest::post()
+                .uri("/check")
+                .cookie(cookie.clone())
+                .set_payload(submitted.to_string())
+                .to_request();
+            assert_eq!(call_service(&app, req).await.status(), expected, "{submitted:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn every_form_page_carries_a_csrf_token() {
+        let mut client = client(Config::for_tests()).await;
+
+        for page in ["/login", "/register", "/contact"] {
+            let token = client.get(page).await.csrf_token();
+            assert!(token.chars().all(|c| c.is_ascii_hexdigit()), "{page}: {token:?}");
+        }
+
+        client.sign_up("ada@example.com", "ada").await;
+        for page in ["/profile", "/account/verify-password", "/logout"] {
+            let token = client.get(page).await.csrf_token();
+            assert!(token.chars().all(|c| c.is_ascii_hexdigit()), "{page}: {token:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn failed_form_posts_get_a_page_linking_back() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+
+        let post = TestRequest::post()
+            .uri("/account/refresh-session")
+            .insert_header((header::REFERER, "http://localhost:8080/profile?tab=security"))
+            .set_form([("csrf_token", "stale")]);
+        let reply = client.send(post).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+        assert!(reply.headers.get(header::CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/html"));
+        assert!(reply.body.contains("Please Try Again"));
+        assert!(reply.body.contains(r#"href="&#x2F;profile?tab=security""#));
+
+        // Only a same-origin page is linked back to
+        let post = TestRequest::post()
+            .uri("/account/refresh-session")
+            .insert_header((header::REFERER, "https://evil.example/profile"))
+            .set_form([("csrf_token", "stale")]);
+        let reply = client.send(post).await;
+        assert!(reply.body.contains(r#"href="&#x2F;""#));
+    }
+
+    #[actix_web::test]
+    async fn failed_json_requests_get_a_csrf_failed_error() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+
+        let posts = [
+            TestRequest::post().uri("/api/logout"),
+            TestRequest::post()
+                .uri("/account/refresh-session")
+                .insert_header((header::ACCEPT, "application/json"))
+                .set_form([("csrf_token", "stale")]),
+        ];
+        for post in posts {
+            let reply = client.send(post).await;
+            assert_eq!(reply.status, StatusCode::FORBIDDEN);
+            let error: Value = serde_json::from_str(&reply.body).unwrap();
+            assert_eq!(error["error"]["code"], "csrf_failed");
+        }
+    }
+}
@@ -1,24 +1,97 @@
-use sqlx::{sqlite::SqlitePool, migrate::MigrateDatabase, Sqlite, Row};
-use crate::models::{User, RegisterForm};
-use bcrypt::{hash, verify, DEFAULT_COST};
-use anyhow::Result;
+use sqlx::{sqlite::{SqlitePool, SqlitePoolOptions, SqliteRow}, migrate::MigrateDatabase, Sqlite, Row};
+use crate::config::Config;
+use crate::features;
+use crate::query_stats;
+use crate::models::{BenchRun, User, UserEmail, UserSummary, RegisterForm, ContactForm, ProfilePatch};
+use bcrypt::{hash, verify};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const USER_COLUMNS: &str = "id, email, username, password_hash, first_name, last_name, avatar_url, is_active, created_at, updated_at, username_changed_at, login_count, last_login_at, bio, profile_public, display_name, timezone";
+
+/// Most `bench_runs` rows kept; older runs are pruned as new ones arrive.
+const MAX_BENCH_RUNS: i64 = 1000;
+
+/// Ids bound per `IN (...)` query in `get_users_by_ids`.
+const MAX_BOUND_IDS: usize = 500;
+
+/// Feature flag values and when they were read, shared by clones of
+/// `Database`.
+type FeatureCache = Arc<Mutex<Option<(Instant, HashMap<String, bool>)>>>;
+
+/// Accounts whose `field` (`email` or `username`) differs only by letter
+/// case. `accounts` holds `(id, value)` pairs, oldest account first.
+#[derive(Debug)]
+pub struct CaseConflict {
+    pub field: &'static str,
+    pub accounts: Vec<(String, String)>,
+}
+
+/// Connection counts from the pool itself, for spotting exhaustion.
+#[derive(Debug, Serialize)]
+pub struct PoolStatus {
+    pub size: u32,
+    pub idle: usize,
+    pub in_use: usize,
+}
 
 #[derive(Clone)]
 pub struct Database {
     pool: SqlitePool,
+    slow_query_threshold: Duration,
+    pepper: Option<String>,
+    login_with_verified_emails: bool,
+    feature_cache: FeatureCache,
 }
 
 impl Database {
-    pub async fn new() -> Result<Self> {
-        let database_url = "sqlite://users.db";
+    pub async fn new(config: &Config) -> Result<Self> {
+        let database_url = config.database_url.as_str();
+
+        // SQLite creates the file but not missing parent directories
+        if let Some(dir) = sqlite_path(database_url).as_deref().and_then(Path::parent) {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                fs::create_dir_all(dir).with_context(|| {
+                    format!("could not create database directory {}", dir.display())
+                })?;
+            }
+        }
         
         // Create database if it doesn't exist
         if !Sqlite::database_exists(database_url).await.unwrap_or(false) {
             println!("Creating database {}", database_url);
-            Sqlite::create_database(database_url).await?;
+            Sqlite::create_database(database_url)
+                .await
+                .with_context(|| format!("could not create database {} (is the directory writable?)", database_url))?;
         }
 
-        let pool = SqlitePool::connect(database_url).await?;
+        let pragmas = config.sqlite_pragmas.clone();
+        let pool = SqlitePoolOptions::new()
+            .after_connect(move |conn, _meta| {
+                let pragmas = pragmas.clone();
+                Box::pin(async move {
+                    for (name, value) in &pragmas {
+                        // Checked in `Config` to be plain words, safe to inline
+                        sqlx::query(&format!("PRAGMA {} = {}", name, value))
+                            .execute(&mut *conn)
+                            .await?;
+                    }
+                    Ok(())
+                })
+            })
+            .connect(database_url)
+            .await
+            .with_context(|| format!("could not open database {}", database_url))?;
         
         // Create tables if they don't exist (simple migration)
         sqlx::query(
@@ -33,12 +106,28 @@ impl Database {
                 avatar_url TEXT,
                 is_active BOOLEAN NOT NULL DEFAULT 1,
                 created_at DATETIME NOT NULL DEFAULT (datetime('now')),
-                updated_at DATETIME NOT NULL DEFAULT (datetime('now'))
+                updated_at DATETIME NOT NULL DEFAULT (datetime('now')),
+                username_changed_at DATETIME,
+                login_count INTEGER NOT NULL DEFAULT 0,
+                last_login_at DATETIME,
+                bio TEXT,
+                profile_public BOOLEAN NOT NULL DEFAULT 0,
+                display_name TEXT,
+                timezone TEXT NOT NULL DEFAULT 'UTC'
             )
             "#
         )
         .execute(&pool)
         .await?;
+
+        // Columns added after the initial schema, for databases created before them
+        add_column_if_missing(&pool, "users", "username_changed_at", "DATETIME").await?;
+        add_column_if_missing(&pool, "users", "login_count", "INTEGER NOT NULL DEFAULT 0").await?;
+        add_column_if_missing(&pool, "users", "last_login_at", "DATETIME").await?;
+        add_column_if_missing(&pool, "users", "bio", "TEXT").await?;
+        add_column_if_missing(&pool, "users", "profile_public", "BOOLEAN NOT NULL DEFAULT 0").await?;
+        add_column_if_missing(&pool, "users", "display_name", "TEXT").await?;
+        add_column_if_missing(&pool, "users", "timezone", "TEXT NOT NULL DEFAULT 'UTC'").await?;
         
         // Create indexes
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_users_email ON users(email)")
@@ -50,16 +139,140 @@ impl Database {
         sqlx::query("CREATE INDEX IF NOT EXISTS idx_users_active ON users(is_active)")
             .execute(&pool)
             .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS contact_messages (
+                id TEXT PRIMARY KEY NOT NULL,
+                name TEXT NOT NULL,
+                email TEXT NOT NULL,
+                message TEXT NOT NULL,
+                created_at DATETIME NOT NULL DEFAULT (datetime('now'))
+            )
+            "#
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS bench_runs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                kind TEXT NOT NULL,
+                ops INTEGER NOT NULL,
+                seconds REAL NOT NULL,
+                ops_per_sec REAL NOT NULL,
+                created_at DATETIME NOT NULL DEFAULT (datetime('now'))
+            )
+            "#
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS feature_flags (
+                name TEXT PRIMARY KEY NOT NULL,
+                enabled BOOLEAN NOT NULL,
+                updated_at DATETIME NOT NULL DEFAULT (datetime('now'))
+            )
+            "#
+        )
+        .execute(&pool)
+        .await?;
+
+        let emails_existed = table_exists(&pool, "emails").await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS emails (
+                address TEXT PRIMARY KEY NOT NULL,
+                user_id TEXT NOT NULL REFERENCES users(id),
+                is_primary BOOLEAN NOT NULL DEFAULT 0,
+                verified BOOLEAN NOT NULL DEFAULT 0,
+                created_at DATETIME NOT NULL DEFAULT (datetime('now'))
+            )
+            "#
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_emails_user ON emails(user_id)")
+            .execute(&pool)
+            .await?;
+        // Accounts created before the table existed get their primary row,
+        // verified, since they were trusted with it until now and
+        // REQUIRE_VERIFIED_EMAIL would otherwise lock all of them out. Only
+        // done when the table is new, so a row that goes missing later is
+        // never re-added as verified.
+        if !emails_existed {
+            sqlx::query(
+                "INSERT OR IGNORE INTO emails (address, user_id, is_primary, verified, created_at) \
+                 SELECT email, id, 1, 1, created_at FROM users"
+            )
+            .execute(&pool)
+            .await?;
+        }
         
-        Ok(Self { pool })
+        Ok(Self {
+            pool,
+            slow_query_threshold: Duration::from_millis(config.slow_query_ms),
+            pepper: config.password_pepper.clone(),
+            login_with_verified_emails: config.login_with_verified_emails,
+            feature_cache: FeatureCache::default(),
+        })
+    }
+
+    /// The underlying pool, for tests that check rows directly.
+    #[cfg(test)]
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    /// Awaits a query future, logging a warning tagged with `op` when it takes
+    /// longer than the configured slow-query threshold.
+    async fn timed<F: Future>(&self, op: &'static str, query: F) -> F::Output {
+        let start = Instant::now();
+        let output = query.await;
+        let elapsed = start.elapsed();
+        query_stats::record(elapsed);
+
+        if elapsed >= self.slow_query_threshold {
+            log::warn!(
+                "slow query: op={} duration_ms={} threshold_ms={}",
+                op,
+                elapsed.as_millis(),
+                self.slow_query_threshold.as_millis()
+            );
+        }
+
+        output
+    }
+
+    /// The password as handed to bcrypt: HMAC-SHA256 keyed with the pepper
+    /// and hex-encoded (64 bytes, under bcrypt's 72-byte limit), or the
+    /// password itself when no pepper is configured.
+    fn peppered(&self, password: &str) -> String {
+        let Some(pepper) = &self.pepper else {
+            return password.to_string();
+        };
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(pepper.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(password.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
     }
 
     pub async fn create_user(&self, form: RegisterForm) -> Result<User> {
-        let password_hash = hash(&form.password, DEFAULT_COST)?;
+        let form = form.normalized();
+        let password_hash = hash_password(self.peppered(&form.password)).await?;
         
         let user = User::new(form.email, form.username, password_hash);
+        let mut tx = self.pool.begin().await?;
         
-        sqlx::query(
+        let insert = sqlx::query(
             "INSERT INTO users (id, email, username, password_hash, first_name, last_name, is_active, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)"
         )
         .bind(&user.id)
@@ -71,37 +284,54 @@ impl Database {
         .bind(user.is_active)
         .bind(user.created_at)
         .bind(user.updated_at)
-        .execute(&self.pool)
-        .await?;
-        
+        .execute(&mut *tx);
+        self.timed("create_user", insert).await?;
+
+        let primary = sqlx::query(
+            "INSERT INTO emails (address, user_id, is_primary, created_at) VALUES (?1, ?2, 1, ?3)"
+        )
+        .bind(&user.email)
+        .bind(&user.id)
+        .bind(user.created_at)
+        .execute(&mut *tx);
+        self.timed("create_user", primary).await?;
+
+        tx.commit().await?;
         Ok(user)
     }
 
     pub async fn authenticate_user(&self, email: &str, password: &str) -> Result<Option<User>> {
-        let row = sqlx::query(
-            "SELECT id, email, username, password_hash, first_name, last_name, avatar_url, is_active, created_at, updated_at FROM users WHERE email = ?1 AND is_active = 1"
-        )
-        .bind(email)
-        .fetch_optional(&self.pool)
-        .await?;
+        let sql = format!(
+            "SELECT {} FROM users WHERE is_active = 1 AND (email = ?1 OR (?2 AND id IN \
+             (SELECT user_id FROM emails WHERE address = ?1 AND verified = 1)))",
+            USER_COLUMNS
+        );
+        let query = sqlx::query(&sql)
+        .bind(email.trim())
+        .bind(self.login_with_verified_emails)
+        .fetch_optional(&self.pool);
+        let row = self.timed("authenticate_user", query).await?;
 
         if let Some(record) = row {
             let password_hash: String = record.get("password_hash");
-            let is_valid = verify(password, &password_hash)?;
+            let is_valid = verify_password(self.peppered(password), password_hash.clone()).await?;
             
             if is_valid {
-                let user = User {
-                    id: record.get("id"),
-                    email: record.get("email"),
-                    username: record.get("username"),
-                    password_hash,
-                    first_name: record.get("first_name"),
-                    last_name: record.get("last_name"),
-                    avatar_url: record.get("avatar_url"),
-                    is_active: record.get::<i64, _>("is_active") != 0,
-                    created_at: record.get("created_at"),
-                    updated_at: record.get("updated_at"),
-                };
+                let mut user = user_from_row(&record);
+                let now = Utc::now();
+
+                // One statement so concurrent logins can't lose an increment
+                // and the count and timestamp always move together
+                let update = sqlx::query(
+                    "UPDATE users SET login_count = login_count + 1, last_login_at = ?1 WHERE id = ?2"
+                )
+                .bind(now)
+                .bind(&user.id)
+                .execute(&self.pool);
+                self.timed("record_login", update).await?;
+
+                user.login_count += 1;
+                user.last_login_at = Some(now);
                 return Ok(Some(user));
             }
         }
@@ -109,24 +339,1053 @@ impl Database {
         Ok(None)
     }
 
+    pub async fn get_user_by_id(&self, id: &str) -> Result<Option<User>> {
+        let sql = format!("SELECT {} FROM users WHERE id = ?1", USER_COLUMNS);
+        let query = sqlx::query(&sql)
+        .bind(id)
+        .fetch_optional(&self.pool);
+        let row = self.timed("get_user_by_id", query).await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    /// Looks up an active user by username, e.g. for their public profile.
+    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
+        let sql = format!("SELECT {} FROM users WHERE username = ?1 AND is_active = 1", USER_COLUMNS);
+        let query = sqlx::query(&sql)
+        .bind(username)
+        .fetch_optional(&self.pool);
+        let row = self.timed("get_user_by_username", query).await?;
+
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    /// Looks up many users at once for lists that reference user ids, keyed
+    /// by id. Ids with no matching user are simply missing from the map.
+    pub async fn get_users_by_ids(&self, ids: &[String]) -> Result<HashMap<String, UserSummary>> {
+        let mut users = HashMap::with_capacity(ids.len());
+
+        // Stay well under SQLite's bound-parameter limit (999 on older builds)
+        for chunk in ids.chunks(MAX_BOUND_IDS) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let sql = format!("SELECT {} FROM users WHERE id IN ({})", USER_COLUMNS, placeholders);
+            let query = chunk
+                .iter()
+                .fold(sqlx::query(&sql), |query, id| query.bind(id))
+                .fetch_all(&self.pool);
+            let rows = self.timed("get_users_by_ids", query).await?;
+
+            for row in &rows {
+                let user = user_from_row(row);
+                users.insert(user.id.clone(), UserSummary::from(user));
+            }
+        }
+
+        Ok(users)
+    }
+
+    pub async fn update_personal_info(
+        &self,
+        user_id: &str,
+        first_name: Option<&str>,
+        last_name: Option<&str>,
+        display_name: Option<&str>,
+        timezone: &str,
+    ) -> Result<()> {
+        let update = sqlx::query(
+            "UPDATE users SET first_name = ?1, last_name = ?2, display_name = ?3, timezone = ?4, updated_at = ?5 WHERE id = ?6"
+        )
+        .bind(first_name)
+        .bind(last_name)
+        .bind(display_name)
+        .bind(timezone)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&self.pool);
+        self.timed("update_personal_info", update).await?;
+
+        Ok(())
+    }
+
+    pub async fn update_public_profile(&self, user_id: &str, bio: Option<&str>, profile_public: bool) -> Result<()> {
+        let update = sqlx::query(
+            "UPDATE users SET bio = ?1, profile_public = ?2, updated_at = ?3 WHERE id = ?4"
+        )
+        .bind(bio)
+        .bind(profile_public)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&self.pool);
+        self.timed("update_public_profile", update).await?;
+
+        Ok(())
+    }
+
+    /// Applies the fields present in `patch` and leaves the others as they
+    /// are. The patch is expected to be normalized and validated already.
+    pub async fn update_user(&self, user_id: &str, patch: &ProfilePatch) -> Result<()> {
+        // Column names only ever come from this list
+        let changes: Vec<(&str, Option<&str>)> = [
+            ("first_name", &patch.first_name),
+            ("last_name", &patch.last_name),
+            ("display_name", &patch.display_name),
+            ("avatar_url", &patch.avatar_url),
+            ("bio", &patch.bio),
+        ]
+        .into_iter()
+        .filter_map(|(column, value)| value.as_ref().map(|value| (column, value.as_deref())))
+        .collect();
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let assignments: Vec<String> = changes
+            .iter()
+            .enumerate()
+            .map(|(i, (column, _))| format!("{} = ?{}", column, i + 1))
+            .collect();
+        let sql = format!(
+            "UPDATE users SET {}, updated_at = ?{} WHERE id = ?{}",
+            assignments.join(", "),
+            changes.len() + 1,
+            changes.len() + 2
+        );
+
+        let update = changes
+            .iter()
+            .fold(sqlx::query(&sql), |query, (_, value)| query.bind(*value))
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&self.pool);
+        self.timed("update_user", update).await?;
+
+        Ok(())
+    }
+
+    /// Renames a user in one UPDATE, letting the unique constraint decide
+    /// whether the name is free. Returns false when it is already taken, so
+    /// two concurrent renames to the same name can't both succeed.
+    pub async fn try_rename_username(&self, user_id: &str, username: &str) -> Result<bool> {
+        let now = Utc::now();
+        let update = sqlx::query(
+            "UPDATE users SET username = ?1, username_changed_at = ?2, updated_at = ?2 WHERE id = ?3"
+        )
+        .bind(username)
+        .bind(now)
+        .bind(user_id)
+        .execute(&self.pool);
+
+        match self.timed("try_rename_username", update).await {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Hashes a password the way it is stored: peppered, then bcrypt. Use
+    /// it to build the hash for `update_password`.
+    pub async fn hash_password(&self, password: &str) -> Result<String> {
+        hash_password(self.peppered(password)).await
+    }
+
+    /// Replaces a user's password hash, as made by `hash_password`.
+    pub async fn update_password(&self, user_id: &str, new_hash: &str) -> Result<()> {
+        let update = sqlx::query("UPDATE users SET password_hash = ?1, updated_at = ?2 WHERE id = ?3")
+            .bind(new_hash)
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&self.pool);
+        self.timed("update_password", update).await?;
+
+        Ok(())
+    }
+
+    /// Checks `password` against the stored hash of an existing user, for
+    /// re-prompting before sensitive actions.
+    pub async fn check_password(&self, user_id: &str, password: &str) -> Result<bool> {
+        match self.get_user_by_id(user_id).await? {
+            Some(user) => verify_password(self.peppered(password), user.password_hash).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Whether `email` is taken, as any account's primary or secondary
+    /// address.
     pub async fn email_exists(&self, email: &str) -> Result<bool> {
-        let row = sqlx::query("SELECT COUNT(*) as count FROM users WHERE email = ?1")
+        let query = sqlx::query(
+            "SELECT (SELECT COUNT(*) FROM users WHERE email = ?1) + \
+             (SELECT COUNT(*) FROM emails WHERE address = ?1) AS count"
+        )
             .bind(email)
-            .fetch_one(&self.pool)
-            .await?;
+            .fetch_one(&self.pool);
+        let row = self.timed("email_exists", query).await?;
         
         let count: i64 = row.get("count");
         Ok(count > 0)
     }
 
+    /// All addresses of an account, primary first.
+    pub async fn list_emails(&self, user_id: &str) -> Result<Vec<UserEmail>> {
+        let query = sqlx::query(
+            "SELECT address, is_primary, verified, created_at FROM emails \
+             WHERE user_id = ?1 ORDER BY is_primary DESC, created_at"
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool);
+        let rows = self.timed("list_emails", query).await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| UserEmail {
+                address: row.get("address"),
+                is_primary: row.get("is_primary"),
+                verified: row.get("verified"),
+                created_at: row.get("created_at"),
+            })
+            .collect())
+    }
+
+    /// Whether the account's primary address is verified, read fresh from
+    /// the database.
+    pub async fn primary_email_verified(&self, user_id: &str) -> Result<bool> {
+        let query = sqlx::query("SELECT verified FROM emails WHERE user_id = ?1 AND is_primary = 1")
+            .bind(user_id)
+            .fetch_optional(&self.pool);
+        let row = self.timed("primary_email_verified", query).await?;
+
+        Ok(row.is_some_and(|row| row.get("verified")))
+    }
+
+    /// Adds an unverified secondary address. Returns false when the address
+    /// already belongs to an account, this one included.
+    pub async fn add_email(&self, user_id: &str, address: &str) -> Result<bool> {
+        // users.email is checked too, for primaries whose row predates the table
+        if self.email_exists(address.trim()).await? {
+            return Ok(false);
+        }
+
+        let insert = sqlx::query("INSERT INTO emails (address, user_id, created_at) VALUES (?1, ?2, ?3)")
+            .bind(address.trim())
+            .bind(user_id)
+            .bind(Utc::now())
+            .execute(&self.pool);
+
+        match self.timed("add_email", insert).await {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Marks one of the account's addresses as verified. Returns false if
+    /// the account has no such address.
+    pub async fn verify_email(&self, user_id: &str, address: &str) -> Result<bool> {
+        let update = sqlx::query("UPDATE emails SET verified = 1 WHERE user_id = ?1 AND address = ?2")
+            .bind(user_id)
+            .bind(address.trim())
+            .execute(&self.pool);
+
+        Ok(self.timed("verify_email", update).await?.rows_affected() > 0)
+    }
+
+    /// Makes a verified address the account's primary, updating
+    /// `users.email` to match; the old primary stays on as a secondary.
+    /// Returns false if the address isn't a verified one of this account.
+    pub async fn set_primary_email(&self, user_id: &str, address: &str) -> Result<bool> {
+        let address = address.trim();
+        let mut tx = self.pool.begin().await?;
+
+        let verified = sqlx::query(
+            "SELECT COUNT(*) AS count FROM emails WHERE user_id = ?1 AND address = ?2 AND verified = 1"
+        )
+        .bind(user_id)
+        .bind(address)
+        .fetch_one(&mut *tx);
+        let verified: i64 = self.timed("set_primary_email", verified).await?.get("count");
+        if verified == 0 {
+            return Ok(false);
+        }
+
+        let flags = sqlx::query("UPDATE emails SET is_primary = (address = ?2) WHERE user_id = ?1")
+            .bind(user_id)
+            .bind(address)
+            .execute(&mut *tx);
+        self.timed("set_primary_email", flags).await?;
+
+        let user = sqlx::query("UPDATE users SET email = ?1, updated_at = ?2 WHERE id = ?3")
+            .bind(address)
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&mut *tx);
+        self.timed("set_primary_email", user).await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Removes a secondary address. The primary can't be removed; returns
+    /// false if the address isn't a secondary of this account.
+    pub async fn remove_email(&self, user_id: &str, address: &str) -> Result<bool> {
+        let delete = sqlx::query("DELETE FROM emails WHERE user_id = ?1 AND address = ?2 AND is_primary = 0")
+            .bind(user_id)
+            .bind(address.trim())
+            .execute(&self.pool);
+
+        Ok(self.timed("remove_email", delete).await?.rows_affected() > 0)
+    }
+
     pub async fn username_exists(&self, username: &str) -> Result<bool> {
-        let row = sqlx::query("SELECT COUNT(*) as count FROM users WHERE username = ?1")
+        let query = sqlx::query("SELECT COUNT(*) as count FROM users WHERE username = ?1")
             .bind(username)
-            .fetch_one(&self.pool)
-            .await?;
+            .fetch_one(&self.pool);
+        let row = self.timed("username_exists", query).await?;
         
         let count: i64 = row.get("count");
         Ok(count > 0)
     }
 
-}
\ No newline at end of file
+    /// Finds emails and usernames shared by several accounts when compared
+    /// case-insensitively, e.g. `Alice` and `alice`.
+    pub async fn find_case_conflicts(&self) -> Result<Vec<CaseConflict>> {
+        let mut conflicts = Vec::new();
+
+        for field in ["email", "username"] {
+            // `field` only ever comes from the list above
+            let sql = format!(
+                "SELECT id, {field} AS value FROM users \
+                 WHERE lower({field}) IN (SELECT lower({field}) FROM users GROUP BY lower({field}) HAVING COUNT(*) > 1) \
+                 ORDER BY lower({field}), created_at",
+                field = field
+            );
+            let query = sqlx::query(&sql).fetch_all(&self.pool);
+            let rows = self.timed("find_case_conflicts", query).await?;
+
+            for row in rows {
+                let account: (String, String) = (row.get("id"), row.get("value"));
+                match conflicts.last_mut() {
+                    Some(CaseConflict { field: last_field, accounts })
+                        if *last_field == field && accounts[0].1.to_lowercase() == account.1.to_lowercase() =>
+                    {
+                        accounts.push(account)
+                    }
+                    _ => conflicts.push(CaseConflict { field, accounts: vec![account] }),
+                }
+            }
+        }
+
+        Ok(conflicts)
+    }
+
+    /// Folds a case-variant duplicate into the account being kept: its login
+    /// count is added to the kept account, its email addresses move over as
+    /// secondary addresses, and it is deactivated, in one transaction. The
+    /// dropped row is kept (soft delete) so its id still resolves, but it can
+    /// no longer log in.
+    pub async fn merge_accounts(&self, keep_id: &str, drop_id: &str) -> Result<()> {
+        if keep_id == drop_id {
+            anyhow::bail!("cannot merge an account into itself");
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        let related = sqlx::query(
+            "SELECT COUNT(*) AS count FROM users AS keep, users AS dropped \
+             WHERE keep.id = ?1 AND dropped.id = ?2 AND dropped.is_active = 1 \
+             AND (lower(keep.email) = lower(dropped.email) OR lower(keep.username) = lower(dropped.username))"
+        )
+        .bind(keep_id)
+        .bind(drop_id)
+        .fetch_one(&mut *tx);
+        let related: i64 = self.timed("merge_accounts", related).await?.get("count");
+        if related == 0 {
+            anyhow::bail!("{} and {} are not active case variants of each other", keep_id, drop_id);
+        }
+
+        let now = Utc::now();
+        let fold = sqlx::query(
+            "UPDATE users SET login_count = login_count + (SELECT login_count FROM users WHERE id = ?2), \
+             updated_at = ?3 WHERE id = ?1"
+        )
+        .bind(keep_id)
+        .bind(drop_id)
+        .bind(now)
+        .execute(&mut *tx);
+        self.timed("merge_accounts", fold).await?;
+
+        let move_emails = sqlx::query("UPDATE emails SET user_id = ?1, is_primary = 0 WHERE user_id = ?2")
+            .bind(keep_id)
+            .bind(drop_id)
+            .execute(&mut *tx);
+        self.timed("merge_accounts", move_emails).await?;
+
+        let deactivate = sqlx::query("UPDATE users SET is_active = 0, updated_at = ?2 WHERE id = ?1")
+            .bind(drop_id)
+            .bind(now)
+            .execute(&mut *tx);
+        self.timed("merge_accounts", deactivate).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Deactivates active accounts whose last login, or sign-up if they
+    /// never logged in, is older than `before`. Returns how many were
+    /// deactivated.
+    pub async fn deactivate_inactive(&self, before: DateTime<Utc>) -> Result<u64> {
+        // datetime() so the column defaults and bound chrono values compare
+        // in the same format
+        let update = sqlx::query(
+            "UPDATE users SET is_active = 0, updated_at = ?2 \
+             WHERE is_active = 1 AND datetime(COALESCE(last_login_at, created_at)) < datetime(?1)"
+        )
+        .bind(before)
+        .bind(Utc::now())
+        .execute(&self.pool);
+        let result = self.timed("deactivate_inactive", update).await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Lists deactivated accounts as `(id, email, last seen)`, most recently
+    /// seen first. Last seen is the last login, or sign-up for accounts that
+    /// never logged in.
+    pub async fn list_inactive(&self) -> Result<Vec<(String, String, DateTime<Utc>)>> {
+        let query = sqlx::query(
+            "SELECT id, email, COALESCE(last_login_at, created_at) AS last_seen FROM users \
+             WHERE is_active = 0 ORDER BY datetime(last_seen) DESC"
+        )
+        .fetch_all(&self.pool);
+        let rows = self.timed("list_inactive", query).await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("id"), row.get("email"), row.get("last_seen")))
+            .collect())
+    }
+
+    /// Reactivates a deactivated account. Returns false when no inactive
+    /// account has that id.
+    pub async fn reactivate_user(&self, user_id: &str) -> Result<bool> {
+        let update = sqlx::query("UPDATE users SET is_active = 1, updated_at = ?2 WHERE id = ?1 AND is_active = 0")
+            .bind(user_id)
+            .bind(Utc::now())
+            .execute(&self.pool);
+        let result = self.timed("reactivate_user", update).await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Every known feature flag with its current value: the stored one, or
+    /// the default from `features::FLAGS`. Cached for `features::CACHE_TTL`.
+    pub async fn feature_flags(&self) -> Result<HashMap<String, bool>> {
+        if let Some((read_at, flags)) = &*self.feature_cache.lock().unwrap_or_else(|e| e.into_inner()) {
+            if read_at.elapsed() < features::CACHE_TTL {
+                return Ok(flags.clone());
+            }
+        }
+
+        let query = sqlx::query("SELECT name, enabled FROM feature_flags").fetch_all(&self.pool);
+        let rows = self.timed("feature_flags", query).await?;
+
+        let mut flags: HashMap<String, bool> = features::FLAGS
+            .iter()
+            .map(|&(name, default)| (name.to_string(), default))
+            .collect();
+        for row in &rows {
+            let name: String = row.get("name");
+            if features::is_known(&name) {
+                flags.insert(name, row.get("enabled"));
+            }
+        }
+
+        *self.feature_cache.lock().unwrap_or_else(|e| e.into_inner()) = Some((Instant::now(), flags.clone()));
+        Ok(flags)
+    }
+
+    /// Whether a feature flag is on. Unknown flags are off.
+    pub async fn is_feature_enabled(&self, name: &str) -> Result<bool> {
+        Ok(self.feature_flags().await?.get(name).copied().unwrap_or(false))
+    }
+
+    /// Stores a flag's value. This process sees it immediately; other
+    /// instances once their cache expires.
+    pub async fn set_feature_flag(&self, name: &str, enabled: bool) -> Result<()> {
+        let upsert = sqlx::query(
+            "INSERT INTO feature_flags (name, enabled, updated_at) VALUES (?1, ?2, ?3) \
+             ON CONFLICT(name) DO UPDATE SET enabled = excluded.enabled, updated_at = excluded.updated_at"
+        )
+        .bind(name)
+        .bind(enabled)
+        .bind(Utc::now())
+        .execute(&self.pool);
+        self.timed("set_feature_flag", upsert).await?;
+
+        *self.feature_cache.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        Ok(())
+    }
+
+    /// Stores a benchmark run and prunes runs beyond the newest
+    /// `MAX_BENCH_RUNS`.
+    pub async fn record_bench_run(&self, kind: &str, ops: u64, seconds: f64, ops_per_sec: f64) -> Result<()> {
+        let insert = sqlx::query(
+            "INSERT INTO bench_runs (kind, ops, seconds, ops_per_sec, created_at) VALUES (?1, ?2, ?3, ?4, ?5)"
+        )
+        .bind(kind)
+        .bind(i64::try_from(ops).unwrap_or(i64::MAX))
+        .bind(seconds)
+        .bind(ops_per_sec)
+        .bind(Utc::now())
+        .execute(&self.pool);
+        self.timed("record_bench_run", insert).await?;
+
+        let prune = sqlx::query(
+            "DELETE FROM bench_runs WHERE id <= (SELECT id FROM bench_runs ORDER BY id DESC LIMIT 1 OFFSET ?1)"
+        )
+        .bind(MAX_BENCH_RUNS)
+        .execute(&self.pool);
+        self.timed("record_bench_run", prune).await?;
+
+        Ok(())
+    }
+
+    /// The newest `limit` benchmark runs, newest first.
+    pub async fn recent_bench_runs(&self, limit: i64) -> Result<Vec<BenchRun>> {
+        let query = sqlx::query(
+            "SELECT kind, ops, seconds, ops_per_sec, created_at FROM bench_runs ORDER BY id DESC LIMIT ?1"
+        )
+        .bind(limit)
+        .fetch_all(&self.pool);
+        let rows = self.timed("recent_bench_runs", query).await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| BenchRun {
+                kind: row.get("kind"),
+                ops: row.get("ops"),
+                seconds: row.get("seconds"),
+                ops_per_sec: row.get("ops_per_sec"),
+                created_at: row.get("created_at"),
+            })
+            .collect())
+    }
+
+    pub async fn save_contact_message(&self, form: &ContactForm) -> Result<()> {
+        let insert = sqlx::query(
+            "INSERT INTO contact_messages (id, name, email, message, created_at) VALUES (?1, ?2, ?3, ?4, ?5)"
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(form.name.trim())
+        .bind(form.email.trim())
+        .bind(form.message.trim())
+        .bind(Utc::now())
+        .execute(&self.pool);
+        self.timed("save_contact_message", insert).await?;
+
+        Ok(())
+    }
+
+    /// Current pool counts. Reads sqlx's counters only; no query is run, so
+    /// this still answers when every connection is busy.
+    pub fn pool_status(&self) -> PoolStatus {
+        let size = self.pool.size();
+        let idle = self.pool.num_idle();
+        PoolStatus {
+            size,
+            idle,
+            in_use: (size as usize).saturating_sub(idle),
+        }
+    }
+
+}
+
+/// File path of a `sqlite:` URL such as `sqlite://data/app.db?mode=rwc`, or
+/// `None` for in-memory databases.
+fn sqlite_path(url: &str) -> Option<PathBuf> {
+    let rest = url.strip_prefix("sqlite://").or_else(|| url.strip_prefix("sqlite:"))?;
+    let path = rest.split('?').next().unwrap_or_default();
+
+    match path {
+        "" | ":memory:" => None,
+        path => Some(PathBuf::from(path)),
+    }
+}
+
+fn user_from_row(record: &SqliteRow) -> User {
+    User {
+        id: record.get("id"),
+        email: record.get("email"),
+        username: record.get("username"),
+        password_hash: record.get("password_hash"),
+        first_name: record.get("first_name"),
+        last_name: record.get("last_name"),
+        avatar_url: record.get("avatar_url"),
+        is_active: record.get::<i64, _>("is_active") != 0,
+        created_at: record.get("created_at"),
+        updated_at: record.get("updated_at"),
+        username_changed_at: record.get("username_changed_at"),
+        login_count: record.get("login_count"),
+        last_login_at: record.get("last_login_at"),
+        bio: record.get("bio"),
+        profile_public: record.get("profile_public"),
+        display_name: record.get("display_name"),
+        timezone: record.get("timezone"),
+    }
+}
+
+async fn table_exists(pool: &SqlitePool, table: &str) -> Result<bool> {
+    let row = sqlx::query("SELECT COUNT(*) AS count FROM sqlite_master WHERE type = 'table' AND name = ?1")
+        .bind(table)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.get::<i64, _>("count") > 0)
+}
+
+async fn add_column_if_missing(pool: &SqlitePool, table: &str, column: &str, definition: &str) -> Result<()> {
+    let columns = sqlx::query(&format!("PRAGMA table_info({})", table))
+        .fetch_all(pool)
+        .await?;
+
+    let exists = columns.iter().any(|row| row.get::<String, _>("name") == column);
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(())
+}
+
+// bcrypt at DEFAULT_COST takes a few hundred milliseconds of pure CPU per call.
+// Running it inline on an async worker stalls every other request scheduled on
+// that worker (concurrent registrations queue up behind each other), so both
+// hashing and verification run on the blocking thread pool instead.
+async fn hash_password(password: String) -> Result<String> {
+    Ok(tokio::task::spawn_blocking(move || hash(password, HASH_COST)).await??)
+}
+
+// Tests hash with bcrypt's minimum cost so they don't spend seconds per user
+#[cfg(not(test))]
+const HASH_COST: u32 = bcrypt::DEFAULT_COST;
+#[cfg(test)]
+const HASH_COST: u32 = 4;
+
+async fn verify_password(password: String, password_hash: String) -> Result<bool> {
+    Ok(tokio::task::spawn_blocking(move || verify(password, &password_hash)).await??)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{capture_logs, logged, register_form, PASSWORD};
+
+    async fn test_db() -> Database {
+        Database::new(&Config::for_tests()).await.expect("in-memory database")
+    }
+
+    #[tokio::test]
+    async fn slow_queries_log_a_warning() {
+        capture_logs();
+        let config = Config { slow_query_ms: 10, ..Config::for_tests() };
+        let db = Database::new(&config).await.unwrap();
+
+        db.timed("test_fast_query", async {}).await;
+        db.timed("test_slow_query", tokio::time::sleep(Duration::from_millis(30))).await;
+
+        assert!(logged("op=test_fast_query").is_empty());
+        let slow = logged("op=test_slow_query");
+        assert_eq!(slow.len(), 1);
+        assert!(slow[0].starts_with("WARN slow query:"));
+        assert!(slow[0].contains("threshold_ms=10"));
+    }
+
+    #[tokio::test]
+    async fn registration_stores_trimmed_fields() {
+        let db = test_db().await;
+
+        let user = db.create_user(register_form("  ada@example.com ", " ada  ")).await.unwrap();
+        assert_eq!(user.email, "ada@example.com");
+        assert_eq!(user.username, "ada");
+        assert!(db.authenticate_user("ada@example.com", PASSWORD).await.unwrap().is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_successful_login_is_counted() {
+        let db = test_db().await;
+        let user = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        assert!(db.authenticate_user("ada@example.com", "wrong").await.unwrap().is_none());
+
+        let logins = (0..5).map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move { db.authenticate_user("ada@example.com", PASSWORD).await })
+        });
+        for login in futures_util::future::join_all(logins).await {
+            assert!(login.unwrap().unwrap().is_some());
+        }
+
+        let user = db.get_user_by_id(&user.id).await.unwrap().unwrap();
+        assert_eq!(user.login_count, 5);
+        assert!(user.last_login_at.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_renames_to_one_name_have_one_winner() {
+        let db = test_db().await;
+        let ada = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        let grace = db.create_user(register_form("grace@example.com", "grace")).await.unwrap();
+
+        let renames = [&ada.id, &grace.id].map(|id| {
+            let (db, id) = (db.clone(), id.clone());
+            tokio::spawn(async move { db.try_rename_username(&id, "countess").await })
+        });
+        let won: Vec<bool> = futures_util::future::join_all(renames)
+            .await
+            .into_iter()
+            .map(|rename| rename.unwrap().unwrap())
+            .collect();
+        assert_eq!(won.iter().filter(|&&won| won).count(), 1);
+
+        let ada = db.get_user_by_id(&ada.id).await.unwrap().unwrap();
+        let grace = db.get_user_by_id(&grace.id).await.unwrap().unwrap();
+        let mut names = [ada.username, grace.username];
+        names.sort();
+        assert!(names == ["ada", "countess"] || names == ["countess", "grace"]);
+    }
+
+    #[tokio::test]
+    async fn feature_flag_toggles_reach_other_instances_after_the_ttl() {
+        let db = test_db().await;
+        // Another process on the same database, with its own cache
+        let other = Database { feature_cache: FeatureCache::default(), ..db.clone() };
+        assert!(db.is_feature_enabled("bench").await.unwrap());
+        assert!(!db.is_feature_enabled("no_such_flag").await.unwrap());
+
+        other.set_feature_flag("bench", false).await.unwrap();
+        assert!(!other.is_feature_enabled("bench").await.unwrap());
+        assert!(db.is_feature_enabled("bench").await.unwrap());
+
+        if let Some((read_at, _)) = &mut *db.feature_cache.lock().unwrap() {
+            *read_at -= features::CACHE_TTL;
+        }
+        assert!(!db.is_feature_enabled("bench").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn new_connections_enforce_foreign_keys() {
+        let db = test_db().await;
+        let mut conn = db.pool.acquire().await.unwrap();
+        let enabled: bool = sqlx::query_scalar("PRAGMA foreign_keys").fetch_one(&mut *conn).await.unwrap();
+        assert!(enabled);
+    }
+
+    #[tokio::test]
+    async fn secondary_emails_are_added_verified_and_promoted() {
+        let db = test_db().await;
+        let ada = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        db.create_user(register_form("grace@example.com", "grace")).await.unwrap();
+
+        assert!(db.add_email(&ada.id, " lovelace@example.com ").await.unwrap());
+        for taken in ["lovelace@example.com", "ada@example.com", "grace@example.com"] {
+            assert!(!db.add_email(&ada.id, taken).await.unwrap(), "{taken}");
+        }
+        let emails = db.list_emails(&ada.id).await.unwrap();
+        let addresses: Vec<(&str, bool, bool)> = emails
+            .iter()
+            .map(|email| (email.address.as_str(), email.is_primary, email.verified))
+            .collect();
+        assert_eq!(addresses, [("ada@example.com", true, false), ("lovelace@example.com", false, false)]);
+
+        // Only a verified address can become the primary
+        assert!(!db.set_primary_email(&ada.id, "lovelace@example.com").await.unwrap());
+        assert!(db.verify_email(&ada.id, "lovelace@example.com").await.unwrap());
+        assert!(db.set_primary_email(&ada.id, "lovelace@example.com").await.unwrap());
+
+        let user = db.get_user_by_id(&ada.id).await.unwrap().unwrap();
+        assert_eq!(user.email, "lovelace@example.com");
+        let emails = db.list_emails(&ada.id).await.unwrap();
+        assert_eq!(emails[0].address, "lovelace@example.com");
+        assert!(emails[0].is_primary);
+        assert!(!emails[1].is_primary);
+        assert!(db.authenticate_user("lovelace@example.com", PASSWORD).await.unwrap().is_some());
+
+        // The old primary stays on as a removable secondary; the new one can't go
+        assert!(!db.remove_email(&ada.id, "lovelace@example.com").await.unwrap());
+        assert!(db.remove_email(&ada.id, "ada@example.com").await.unwrap());
+        assert_eq!(db.list_emails(&ada.id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn verified_secondaries_log_in_only_when_enabled() {
+        for enabled in [false, true] {
+            let config = Config { login_with_verified_emails: enabled, ..Config::for_tests() };
+            let db = Database::new(&config).await.unwrap();
+            let ada = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+            db.add_email(&ada.id, "lovelace@example.com").await.unwrap();
+            assert!(db.authenticate_user("lovelace@example.com", PASSWORD).await.unwrap().is_none());
+
+            db.verify_email(&ada.id, "lovelace@example.com").await.unwrap();
+            let login = db.authenticate_user("lovelace@example.com", PASSWORD).await.unwrap();
+            assert_eq!(login.map(|user| user.id), enabled.then(|| ada.id.clone()), "enabled: {enabled}");
+        }
+    }
+
+    #[tokio::test]
+    async fn updated_password_replaces_the_old_one() {
+        let db = test_db().await;
+        let ada = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+
+        let new_hash = db.hash_password("correct horse").await.unwrap();
+        db.update_password(&ada.id, &new_hash).await.unwrap();
+
+        assert!(db.authenticate_user("ada@example.com", PASSWORD).await.unwrap().is_none());
+        assert!(db.authenticate_user("ada@example.com", "correct horse").await.unwrap().is_some());
+        assert!(db.check_password(&ada.id, "correct horse").await.unwrap());
+    }
+
+    #[test]
+    fn sqlite_path_reads_file_urls() {
+        assert_eq!(sqlite_path("sqlite://data/app.db"), Some(PathBuf::from("data/app.db")));
+        assert_eq!(sqlite_path("sqlite:users.db?mode=rwc"), Some(PathBuf::from("users.db")));
+        assert_eq!(sqlite_path("sqlite:///var/lib/app.db"), Some(PathBuf::from("/var/lib/app.db")));
+        assert_eq!(sqlite_path("sqlite::memory:"), None);
+        assert_eq!(sqlite_path("postgres://localhost/app"), None);
+    }
+
+    #[tokio::test]
+    async fn nested_database_paths_are_created() {
+        let root = std::env::temp_dir().join(format!("db-test-{}", uuid::Uuid::new_v4().simple()));
+        let file = root.join("data/nested/app.db");
+        let config = Config {
+            database_url: format!("sqlite://{}", file.display()),
+            ..Config::for_tests()
+        };
+
+        let db = Database::new(&config).await.unwrap();
+        db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        assert!(file.is_file());
+
+        db.pool.close().await;
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn existing_primaries_are_backfilled_as_verified() {
+        let file = std::env::temp_dir().join(format!("db-test-{}.db", uuid::Uuid::new_v4().simple()));
+        let config = Config {
+            database_url: format!("sqlite://{}", file.display()),
+            ..Config::for_tests()
+        };
+
+        let db = Database::new(&config).await.unwrap();
+        let ada = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        // Ada's account predates the emails table
+        sqlx::query("DROP TABLE emails").execute(&db.pool).await.unwrap();
+        db.pool.close().await;
+
+        let db = Database::new(&config).await.unwrap();
+        assert!(db.primary_email_verified(&ada.id).await.unwrap());
+        // Accounts registered since still have to verify
+        let grace = db.create_user(register_form("grace@example.com", "grace")).await.unwrap();
+        assert!(!db.primary_email_verified(&grace.id).await.unwrap());
+
+        // The backfill runs once, so a primary row lost later isn't restored as verified
+        sqlx::query("DELETE FROM emails WHERE user_id = ?1").bind(&grace.id).execute(&db.pool).await.unwrap();
+        db.pool.close().await;
+        let db = Database::new(&config).await.unwrap();
+        assert!(db.list_emails(&grace.id).await.unwrap().is_empty());
+        assert!(!db.primary_email_verified(&grace.id).await.unwrap());
+
+        db.pool.close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", file.display(), suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn peppered_passwords_need_the_same_pepper() {
+        let config = Config { password_pepper: Some("pepper-one".to_string()), ..Config::for_tests() };
+        let db = Database::new(&config).await.unwrap();
+        let user = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+
+        assert!(db.authenticate_user("ada@example.com", PASSWORD).await.unwrap().is_some());
+        assert!(db.authenticate_user("ada@example.com", "wrong").await.unwrap().is_none());
+        // The stored hash is of the HMAC, not the password itself
+        assert!(!verify(PASSWORD, &user.password_hash).unwrap());
+
+        let rotated = Database { pepper: Some("pepper-two".to_string()), ..db.clone() };
+        assert!(rotated.authenticate_user("ada@example.com", PASSWORD).await.unwrap().is_none());
+        let unpeppered = Database { pepper: None, ..db };
+        assert!(unpeppered.authenticate_user("ada@example.com", PASSWORD).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn case_conflicts_are_found_and_merged() {
+        let db = test_db().await;
+        let keep = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        let drop = db.create_user(register_form("Ada@example.com", "lovelace")).await.unwrap();
+        db.create_user(register_form("grace@example.com", "grace")).await.unwrap();
+        db.add_email(&drop.id, "countess@example.com").await.unwrap();
+        db.authenticate_user("Ada@example.com", PASSWORD).await.unwrap();
+
+        let conflicts = db.find_case_conflicts().await.unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].field, "email");
+        assert_eq!(
+            conflicts[0].accounts,
+            [(keep.id.clone(), "ada@example.com".to_string()), (drop.id.clone(), "Ada@example.com".to_string())]
+        );
+
+        db.merge_accounts(&keep.id, &drop.id).await.unwrap();
+
+        let kept = db.get_user_by_id(&keep.id).await.unwrap().unwrap();
+        assert!(kept.is_active);
+        assert_eq!((kept.email.as_str(), kept.username.as_str()), ("ada@example.com", "ada"));
+        assert_eq!(kept.login_count, 1);
+        assert!(!db.get_user_by_id(&drop.id).await.unwrap().unwrap().is_active);
+
+        let emails: Vec<_> = db
+            .list_emails(&keep.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|email| (email.address, email.is_primary))
+            .collect();
+        assert_eq!(emails.len(), 3);
+        assert!(emails.contains(&("ada@example.com".to_string(), true)));
+        assert!(emails.contains(&("Ada@example.com".to_string(), false)));
+        assert!(emails.contains(&("countess@example.com".to_string(), false)));
+        assert!(db.list_emails(&drop.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unrelated_accounts_are_not_merged() {
+        let db = test_db().await;
+        let ada = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        let grace = db.create_user(register_form("grace@example.com", "grace")).await.unwrap();
+
+        assert!(db.merge_accounts(&ada.id, &grace.id).await.is_err());
+        assert!(db.merge_accounts(&ada.id, &ada.id).await.is_err());
+        assert!(db.get_user_by_id(&grace.id).await.unwrap().unwrap().is_active);
+        assert_eq!(db.list_emails(&grace.id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn users_by_ids_skips_missing_ids() {
+        let db = test_db().await;
+        let ada = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        let grace = db.create_user(register_form("grace@example.com", "grace")).await.unwrap();
+
+        let ids = [ada.id.clone(), "missing".to_string(), grace.id.clone(), ada.id.clone()];
+        let users = db.get_users_by_ids(&ids).await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[&ada.id].username, "ada");
+        assert_eq!(users[&grace.id].username, "grace");
+
+        assert!(db.get_users_by_ids(&[]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn users_by_ids_spans_chunks() {
+        let db = test_db().await;
+        let ada = db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        let grace = db.create_user(register_form("grace@example.com", "grace")).await.unwrap();
+
+        // Ada ends the first chunk and Grace starts the second
+        let mut ids: Vec<String> = (0..MAX_BOUND_IDS * 2).map(|i| format!("missing-{}", i)).collect();
+        ids[MAX_BOUND_IDS - 1] = ada.id.clone();
+        ids[MAX_BOUND_IDS] = grace.id.clone();
+
+        let users = db.get_users_by_ids(&ids).await.unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users.contains_key(&ada.id) && users.contains_key(&grace.id));
+    }
+
+    async fn backdate(db: &Database, user_id: &str, column: &str, days: i64) {
+        sqlx::query(&format!("UPDATE users SET {} = ?1 WHERE id = ?2", column))
+            .bind(Utc::now() - chrono::Duration::days(days))
+            .bind(user_id)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_accounts_past_the_threshold_are_deactivated() {
+        let db = test_db().await;
+        let mut accounts = Vec::new();
+        for name in ["stale", "recent", "dormant", "fresh"] {
+            let user = db.create_user(register_form(&format!("{}@example.com", name), name)).await.unwrap();
+            accounts.push(user.id);
+        }
+        let [stale, recent, dormant, fresh] = &accounts[..] else { unreachable!() };
+        // Stale and recent signed up long ago; only recent logged in lately.
+        // Dormant never logged in since signing up long ago.
+        for id in [stale, recent, dormant] {
+            backdate(&db, id, "created_at", 400).await;
+        }
+        backdate(&db, stale, "last_login_at", 100).await;
+        backdate(&db, recent, "last_login_at", 1).await;
+
+        let deactivated = db.deactivate_inactive(Utc::now() - chrono::Duration::days(90)).await.unwrap();
+        assert_eq!(deactivated, 2);
+
+        let mut inactive: Vec<String> = db.list_inactive().await.unwrap().into_iter().map(|(id, _, _)| id).collect();
+        inactive.sort();
+        let mut expected = vec![stale.clone(), dormant.clone()];
+        expected.sort();
+        assert_eq!(inactive, expected);
+        for id in [recent, fresh] {
+            assert!(db.get_user_by_id(id).await.unwrap().unwrap().is_active);
+        }
+
+        assert!(db.reactivate_user(stale).await.unwrap());
+        assert!(!db.reactivate_user(stale).await.unwrap());
+        assert!(db.authenticate_user("stale@example.com", PASSWORD).await.unwrap().is_some());
+    }
+
+    /// Waits for released connections to be back in the pool; they are
+    /// returned on a spawned task.
+    async fn settle(db: &Database) -> PoolStatus {
+        for _ in 0..100 {
+            if db.pool_status().in_use == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        db.pool_status()
+    }
+
+    #[tokio::test]
+    async fn pool_status_counts_acquired_connections() {
+        let db = test_db().await;
+        assert_eq!(settle(&db).await.in_use, 0);
+
+        let conn = db.pool.acquire().await.unwrap();
+        let during = db.pool_status();
+        assert_eq!(during.in_use, 1);
+        assert_eq!(during.size as usize, during.idle + 1);
+
+        drop(conn);
+        assert_eq!(settle(&db).await.in_use, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_registrations_all_succeed() {
+        let db = test_db().await;
+
+        let registrations = (0..8).map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.create_user(register_form(&format!("user{}@example.com", i), &format!("user{}", i))).await
+            })
+        });
+        for registration in futures_util::future::join_all(registrations).await {
+            registration.unwrap().expect("registration");
+        }
+
+        for i in 0..8 {
+            assert!(db.email_exists(&format!("user{}@example.com", i)).await.unwrap());
+        }
+    }
+}
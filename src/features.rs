@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+/// Feature flags the app checks, with the value used while no row for them
+/// is stored in `feature_flags`. Flags are toggled with the `feature`
+/// command.
+pub const FLAGS: &[(&str, bool)] = &[
+    // The `/api/bench` CPU benchmark
+    ("bench", true),
+    // Public profile pages at `/u/{username}`
+    ("public_profiles", true),
+];
+
+/// How long flag values are cached before the table is read again, so a
+/// toggle reaches every running instance within this time.
+pub const CACHE_TTL: Duration = Duration::from_secs(30);
+
+pub fn is_known(name: &str) -> bool {
+    FLAGS.iter().any(|(flag, _)| *flag == name)
+}
@@ -1,91 +1,123 @@
-use actix_web::{web, HttpResponse, Result};
+use actix_web::{http::{header, StatusCode}, web, HttpRequest, HttpResponse, Result};
 use actix_session::Session;
 use tera::{Context, Tera};
 use serde::Deserialize;
-use crate::models::{LoginForm, RegisterForm, SessionUser};
+use chrono::{Duration, Utc};
+use crate::api::ApiError;
+use crate::config::Config;
+use crate::page::{self, Notice, PageContext};
+use crate::redirect;
+use crate::templating::html_response;
+use crate::models::{ChangePasswordForm, CsrfForm, LoginForm, RegisterForm, SessionUser, ContactForm, ChangeUsernameForm, VerifyPasswordForm, PublicProfileForm, ProfileUpdateForm, ProfilePatch, PublicUser, UserSummary, MAX_BIO_CHARS, MAX_NAME_CHARS};
 use crate::database::Database;
-use crate::auth::{login_user, OptionalAuthUser, AuthUser};
+use crate::service::UserService;
+use crate::rate_limit::RateLimit;
+use crate::tarpit::LoginTarpit;
+use crate::csrf::{rotate_csrf_token, verify_csrf, verify_csrf_header, with_csrf};
+use crate::auth::{end_session, grant_sudo, has_sudo, inject_auth, login_user, update_session_user, OptionalAuthUser, AuthUser, RememberMePolicy, VerifiedUser};
 
 #[derive(Deserialize)]
 pub struct QueryMessage {
     msg: Option<String>,
+    next: Option<String>,
+}
+
+impl QueryMessage {
+    fn notice(&self) -> Option<Notice> {
+        page::notice(self.msg.as_deref()?)
+    }
 }
 
 pub async fn login_page(
     tmpl: web::Data<Tera>,
     query: web::Query<QueryMessage>,
     user: OptionalAuthUser,
+    page: PageContext,
+    config: web::Data<Config>,
 ) -> Result<HttpResponse> {
     // Redirect if already logged in
     if user.0.is_some() {
+        let location = redirect::after_login(query.next.as_deref(), &config.default_login_redirect);
         return Ok(HttpResponse::Found()
-            .insert_header(("location", "/dashboard"))
+            .insert_header(("location", location))
             .finish());
     }
 
-    let mut ctx = Context::new();
-    ctx.insert("brand_name", "Rust Web AI");
-    
-    if let Some(msg) = &query.msg {
-        ctx.insert("msg", msg);
+    let mut ctx = page.0;
+
+    // Carried through the form so the POST can send the user back there
+    if let Some(next) = query.next.as_deref().and_then(redirect::safe_next) {
+        ctx.insert("next", &next);
     }
 
     let body = tmpl
         .render("auth/login-simple.html.tera", &ctx)
         .unwrap_or_else(|e| format!("Template error: {e}"));
     
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(body))
+    Ok(html_response(body))
 }
 
 pub async fn login_submit(
+    req: HttpRequest,
     tmpl: web::Data<Tera>,
     form: web::Form<LoginForm>,
     session: Session,
     db: web::Data<Database>,
+    config: web::Data<Config>,
+    tarpit: web::Data<LoginTarpit>,
 ) -> Result<HttpResponse> {
+    verify_csrf(&session, &form.csrf_token)?;
+    let client_ip = req.peer_addr().map(|addr| addr.ip());
+
     let mut ctx = Context::new();
     ctx.insert("brand_name", "Rust Web AI");
-    ctx.insert("form_data", &*form);
+    // Only echo the email back: the password shouldn't reach the template,
+    // and the CSRF token must come from the session via `with_csrf` rather
+    // than the submitted form, so a failed attempt always re-renders with a
+    // token the next attempt will accept.
+    ctx.insert("form_data", &serde_json::json!({ "email": form.email }));
+    with_csrf(&mut ctx, &session)?;
+    if let Some(next) = form.next.as_deref().and_then(redirect::safe_next) {
+        ctx.insert("next", &next);
+    }
 
     // Authenticate user
     match db.authenticate_user(&form.email, &form.password).await {
         Ok(Some(user)) => {
-            let session_user = SessionUser::from(user);
+            tarpit.succeed(&form.email, client_ip);
+            let avatar_url = user.avatar_or_gravatar(config.gravatar_style.as_deref());
+            let mut session_user = SessionUser::from(user);
+            session_user.avatar_url = avatar_url;
             
-            if let Err(e) = login_user(&session, session_user) {
+            let policy = RememberMePolicy::from_form(form.remember_me);
+            if let Err(e) = login_user(&req, &session, session_user, policy) {
                 ctx.insert("error", &format!("Login failed: {}", e));
                 let body = tmpl
                     .render("auth/login-simple.html.tera", &ctx)
                     .unwrap_or_else(|e| format!("Template error: {e}"));
-                return Ok(HttpResponse::Ok()
-                    .content_type("text/html; charset=utf-8")
-                    .body(body));
+                return Ok(html_response(body));
             }
 
-            // Redirect to dashboard
+            // Redirect to the requested page, or the configured landing page
+            let location = redirect::after_login(form.next.as_deref(), &config.default_login_redirect);
             Ok(HttpResponse::Found()
-                .insert_header(("location", "/dashboard"))
+                .insert_header(("location", location))
                 .finish())
         },
         Ok(None) => {
+            tarpit.fail(&form.email, client_ip).await;
             ctx.insert("error", "Invalid email or password");
             let body = tmpl
                 .render("auth/login.html.tera", &ctx)
                 .unwrap_or_else(|e| format!("Template error: {e}"));
-            Ok(HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(body))
+            Ok(html_response(body))
         },
         Err(e) => {
             ctx.insert("error", &format!("Login error: {}", e));
             let body = tmpl
                 .render("auth/login.html.tera", &ctx)
                 .unwrap_or_else(|e| format!("Template error: {e}"));
-            Ok(HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(body))
+            Ok(html_response(body))
         }
     }
 }
@@ -93,94 +125,39 @@ pub async fn login_submit(
 pub async fn register_page(
     tmpl: web::Data<Tera>,
     user: OptionalAuthUser,
+    page: PageContext,
+    config: web::Data<Config>,
 ) -> Result<HttpResponse> {
     // Redirect if already logged in
     if user.0.is_some() {
         return Ok(HttpResponse::Found()
-            .insert_header(("location", "/dashboard"))
+            .insert_header(("location", config.default_login_redirect.as_str()))
             .finish());
     }
 
-    let mut ctx = Context::new();
-    ctx.insert("brand_name", "Rust Web AI");
-
     let body = tmpl
-        .render("auth/register-simple.html.tera", &ctx)
+        .render("auth/register-simple.html.tera", &page.0)
         .unwrap_or_else(|e| format!("Template error: {e}"));
     
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(body))
+    Ok(html_response(body))
 }
 
 pub async fn register_submit(
     tmpl: web::Data<Tera>,
     form: web::Form<RegisterForm>,
-    db: web::Data<Database>,
+    session: Session,
+    users: web::Data<UserService>,
 ) -> Result<HttpResponse> {
-    let mut ctx = Context::new();
-    ctx.insert("brand_name", "Rust Web AI");
-    ctx.insert("form_data", &*form);
-
-    // Validate form
-    if let Err(validation_errors) = form.validate() {
-        ctx.insert("errors", &validation_errors);
-        let body = tmpl
-            .render("auth/register-simple.html.tera", &ctx)
-            .unwrap_or_else(|e| format!("Template error: {e}"));
-        return Ok(HttpResponse::Ok()
-            .content_type("text/html; charset=utf-8")
-            .body(body));
-    }
+    verify_csrf(&session, &form.csrf_token)?;
 
-    // Check if email already exists
-    match db.email_exists(&form.email).await {
-        Ok(true) => {
-            ctx.insert("errors", &vec!["Email address is already registered".to_string()]);
-            let body = tmpl
-                .render("auth/register-simple.html.tera", &ctx)
-                .unwrap_or_else(|e| format!("Template error: {e}"));
-            return Ok(HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(body));
-        },
-        Err(e) => {
-            ctx.insert("errors", &vec![format!("Database error: {}", e)]);
-            let body = tmpl
-                .render("auth/register-simple.html.tera", &ctx)
-                .unwrap_or_else(|e| format!("Template error: {e}"));
-            return Ok(HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(body));
-        },
-        _ => {}
-    }
+    let form = form.into_inner().normalized();
 
-    // Check if username already exists
-    match db.username_exists(&form.username).await {
-        Ok(true) => {
-            ctx.insert("errors", &vec!["Username is already taken".to_string()]);
-            let body = tmpl
-                .render("auth/register-simple.html.tera", &ctx)
-                .unwrap_or_else(|e| format!("Template error: {e}"));
-            return Ok(HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(body));
-        },
-        Err(e) => {
-            ctx.insert("errors", &vec![format!("Database error: {}", e)]);
-            let body = tmpl
-                .render("auth/register-simple.html.tera", &ctx)
-                .unwrap_or_else(|e| format!("Template error: {e}"));
-            return Ok(HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(body));
-        },
-        _ => {}
-    }
+    let mut ctx = Context::new();
+    ctx.insert("brand_name", "Rust Web AI");
+    ctx.insert("form_data", &form);
+    with_csrf(&mut ctx, &session)?;
 
-    // Create user
-    match db.create_user(form.into_inner()).await {
+    match users.register(form).await {
         Ok(_user) => {
             // Redirect to login with success message
             Ok(HttpResponse::Found()
@@ -188,13 +165,11 @@ pub async fn register_submit(
                 .finish())
         },
         Err(e) => {
-            ctx.insert("errors", &vec![format!("Registration failed: {}", e)]);
+            ctx.insert("errors", &e.messages());
             let body = tmpl
                 .render("auth/register-simple.html.tera", &ctx)
                 .unwrap_or_else(|e| format!("Template error: {e}"));
-            Ok(HttpResponse::Ok()
-                .content_type("text/html; charset=utf-8")
-                .body(body))
+            Ok(html_response(body))
         }
     }
 }
@@ -202,12 +177,14 @@ pub async fn register_submit(
 pub async fn dashboard_page(
     tmpl: web::Data<Tera>,
     user: AuthUser,
+    session: Session,
 ) -> Result<HttpResponse> {
     let mut ctx = Context::new();
     ctx.insert("title", "Dashboard");
     ctx.insert("brand_name", "Rust Web AI");
     ctx.insert("user", &user.0);
     ctx.insert("active", "dashboard");
+    with_csrf(&mut ctx, &session)?;
 
     // Add navigation items
     let nav_items = vec![
@@ -230,50 +207,460 @@ pub async fn dashboard_page(
         .render("dashboard-simple.html.tera", &ctx)
         .unwrap_or_else(|e| format!("Template error: {e}"));
     
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(body))
+    Ok(html_response(body))
+}
+
+/// Context shared by the profile page and the forms that re-render it.
+async fn profile_context(user: &SessionUser, session: &Session, db: &Database) -> Result<Context> {
+    let mut ctx = Context::new();
+    ctx.insert("title", "Profile Settings");
+    ctx.insert("brand_name", "Rust Web AI");
+    ctx.insert("user", user);
+    ctx.insert("active", "profile");
+    ctx.insert("max_bio_chars", &MAX_BIO_CHARS);
+    with_csrf(&mut ctx, session)?;
+
+    // Account statistics and public profile settings
+    let account = db.get_user_by_id(&user.id).await.ok().flatten();
+    ctx.insert("login_count", &account.as_ref().map(|a| a.login_count).unwrap_or(0));
+    ctx.insert(
+        "member_since",
+        &account.as_ref().map(|a| a.created_at.format("%b %Y").to_string()).unwrap_or_default(),
+    );
+    ctx.insert("max_name_chars", &MAX_NAME_CHARS);
+    ctx.insert("first_name", &account.as_ref().and_then(|a| a.first_name.clone()).unwrap_or_default());
+    ctx.insert("last_name", &account.as_ref().and_then(|a| a.last_name.clone()).unwrap_or_default());
+    ctx.insert("display_name", &account.as_ref().and_then(|a| a.display_name.clone()).unwrap_or_default());
+    ctx.insert("bio", &account.as_ref().and_then(|a| a.bio.clone()).unwrap_or_default());
+    ctx.insert("profile_public", &account.as_ref().is_some_and(|a| a.profile_public));
+    ctx.insert("timezone", &account.as_ref().map_or("UTC", |a| a.timezone.as_str()));
+    ctx.insert("timezones", &chrono_tz::TZ_VARIANTS.iter().map(|tz| tz.name()).collect::<Vec<_>>());
+    ctx.insert("last_login_at", &account.as_ref().and_then(|a| a.last_login_at));
+    ctx.insert("features", &db.feature_flags().await.unwrap_or_default());
+
+    Ok(ctx)
+}
+
+fn render_profile(tmpl: &Tera, ctx: &Context) -> HttpResponse {
+    let body = tmpl
+        .render("auth/profile.html.tera", ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    html_response(body)
 }
 
 pub async fn profile_page(
     tmpl: web::Data<Tera>,
+    query: web::Query<QueryMessage>,
+    user: AuthUser,
+    session: Session,
+    db: web::Data<Database>,
+) -> Result<HttpResponse> {
+    let mut ctx = profile_context(&user.0, &session, &db).await?;
+
+    if let Some(notice) = query.notice() {
+        ctx.insert("success", notice.text);
+    }
+
+    Ok(render_profile(&tmpl, &ctx))
+}
+
+fn render_verify_password(tmpl: &Tera, ctx: &Context) -> HttpResponse {
+    let body = tmpl
+        .render("auth/verify-password.html.tera", ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+    html_response(body)
+}
+
+pub async fn verify_password_page(
+    tmpl: web::Data<Tera>,
+    query: web::Query<QueryMessage>,
+    _user: AuthUser,
+    page: PageContext,
+) -> Result<HttpResponse> {
+    let mut ctx = page.0;
+    if let Some(next) = query.next.as_deref().and_then(redirect::safe_next) {
+        ctx.insert("next", &next);
+    }
+
+    Ok(render_verify_password(&tmpl, &ctx))
+}
+
+/// Re-checks the current password and, when it matches, unlocks sensitive
+/// actions for a few minutes before sending the user back to `next`.
+pub async fn verify_password_submit(
+    tmpl: web::Data<Tera>,
+    form: web::Form<VerifyPasswordForm>,
     user: AuthUser,
+    session: Session,
+    db: web::Data<Database>,
 ) -> Result<HttpResponse> {
+    verify_csrf(&session, &form.csrf_token)?;
+
+    let error = match db.check_password(&user.0.id, &form.password).await {
+        Ok(true) => {
+            grant_sudo(&session)?;
+            let location = redirect::after_login(form.next.as_deref(), "/profile");
+            return Ok(HttpResponse::Found()
+                .insert_header(("location", location))
+                .finish());
+        }
+        Ok(false) => "Incorrect password".to_string(),
+        Err(e) => format!("Could not verify password: {}", e),
+    };
+
     let mut ctx = Context::new();
-    ctx.insert("title", "Profile Settings");
     ctx.insert("brand_name", "Rust Web AI");
     ctx.insert("user", &user.0);
-    ctx.insert("active", "profile");
+    ctx.insert("error", &error);
+    with_csrf(&mut ctx, &session)?;
+    if let Some(next) = form.next.as_deref().and_then(redirect::safe_next) {
+        ctx.insert("next", &next);
+    }
+
+    Ok(render_verify_password(&tmpl, &ctx))
+}
+
+/// Reissues the session under a new cookie value, keeping the login, and
+/// rotates the CSRF token so tokens from before the refresh stop working.
+pub async fn refresh_session_submit(
+    form: web::Form<CsrfForm>,
+    _user: AuthUser,
+    session: Session,
+) -> Result<HttpResponse> {
+    verify_csrf(&session, &form.csrf_token)?;
+
+    session.renew();
+    rotate_csrf_token(&session);
+
+    Ok(HttpResponse::Found()
+        .insert_header(("location", "/profile?msg=session_refreshed"))
+        .finish())
+}
+
+/// GET /logout only asks for confirmation; following a link or a prefetch
+/// must never end the session.
+pub async fn logout_page(
+    tmpl: web::Data<Tera>,
+    user: OptionalAuthUser,
+    session: Session,
+) -> Result<HttpResponse> {
+    if user.0.is_none() {
+        return Ok(HttpResponse::Found()
+            .insert_header(("location", "/login"))
+            .finish());
+    }
+
+    confirm_logout(&tmpl, &session)
+}
+
+pub async fn logout_submit(
+    tmpl: web::Data<Tera>,
+    form: Option<web::Form<CsrfForm>>,
+    user: OptionalAuthUser,
+    session: Session,
+) -> Result<HttpResponse> {
+    // A stale or missing token shouldn't trap anyone in their session: ask
+    // them to confirm with a fresh token instead of answering 403. Logged-out
+    // visitors have nothing to protect and go straight through.
+    let token = form.map(|form| form.into_inner().csrf_token).unwrap_or_default();
+    if user.0.is_some() && verify_csrf(&session, &token).is_err() {
+        return confirm_logout(&tmpl, &session);
+    }
+
+    end_session(&session);
+    Ok(HttpResponse::Found()
+        .insert_header(("location", "/login?msg=logged_out"))
+        .finish())
+}
+
+fn confirm_logout(tmpl: &Tera, session: &Session) -> Result<HttpResponse> {
+    let mut ctx = Context::new();
+    ctx.insert("title", "Log out");
+    ctx.insert("brand_name", "Rust Web AI");
+    with_csrf(&mut ctx, session)?;
 
     let body = tmpl
-        .render("auth/profile.html.tera", &ctx)
+        .render("auth/confirm-logout.html.tera", &ctx)
         .unwrap_or_else(|e| format!("Template error: {e}"));
-    
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(body))
+
+    Ok(html_response(body))
+}
+
+pub async fn change_username_submit(
+    tmpl: web::Data<Tera>,
+    form: web::Form<ChangeUsernameForm>,
+    user: VerifiedUser,
+    session: Session,
+    db: web::Data<Database>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    verify_csrf(&session, &form.csrf_token)?;
+
+    if !has_sudo(&session) {
+        return Ok(HttpResponse::Found()
+            .insert_header(("location", "/account/verify-password?next=/profile"))
+            .finish());
+    }
+
+    let mut ctx = profile_context(&user.0, &session, &db).await?;
+
+    let username = form.username.trim();
+
+    let error = if let Err(validation_errors) = form.validate() {
+        Some(validation_errors.join(", "))
+    } else if username == user.0.username {
+        Some("That is already your username".to_string())
+    } else {
+        match db.get_user_by_id(&user.0.id).await {
+            Ok(Some(current)) => {
+                let cooldown = Duration::days(config.username_change_cooldown_days);
+                let next_change = current.username_changed_at.map(|changed_at| changed_at + cooldown);
+
+                match next_change {
+                    Some(next_change) if next_change > Utc::now() => {
+                        let remaining = next_change - Utc::now();
+                        Some(format!(
+                            "You can change your username again in {} day(s)",
+                            remaining.num_days() + 1
+                        ))
+                    }
+                    _ => match db.try_rename_username(&user.0.id, username).await {
+                        Ok(true) => None,
+                        Ok(false) => Some("Username is already taken".to_string()),
+                        Err(e) => Some(format!("Could not update username: {}", e)),
+                    },
+                }
+            }
+            Ok(None) => Some("Account not found".to_string()),
+            Err(e) => Some(format!("Database error: {}", e)),
+        }
+    };
+
+    if let Some(error) = error {
+        ctx.insert("error", &error);
+        return Ok(render_profile(&tmpl, &ctx));
+    }
+
+    // Keep the session in sync with the new username
+    let mut session_user = user.0;
+    session_user.username = username.to_string();
+    update_session_user(&session, session_user)?;
+
+    Ok(HttpResponse::Found()
+        .insert_header(("location", "/profile?msg=username_changed"))
+        .finish())
+}
+
+pub async fn change_password_submit(
+    tmpl: web::Data<Tera>,
+    form: web::Form<ChangePasswordForm>,
+    user: VerifiedUser,
+    session: Session,
+    db: web::Data<Database>,
+) -> Result<HttpResponse> {
+    verify_csrf(&session, &form.csrf_token)?;
+
+    let error = if let Err(validation_errors) = form.validate() {
+        Some(validation_errors.join(", "))
+    } else {
+        match db.check_password(&user.0.id, &form.current_password).await {
+            Ok(true) => {
+                let updated = match db.hash_password(&form.new_password).await {
+                    Ok(new_hash) => db.update_password(&user.0.id, &new_hash).await,
+                    Err(e) => Err(e),
+                };
+                updated.err().map(|e| format!("Could not update password: {}", e))
+            }
+            Ok(false) => Some("Current password is incorrect".to_string()),
+            Err(e) => Some(format!("Database error: {}", e)),
+        }
+    };
+
+    if let Some(error) = error {
+        let mut ctx = profile_context(&user.0, &session, &db).await?;
+        ctx.insert("error", &error);
+        return Ok(render_profile(&tmpl, &ctx));
+    }
+
+    // New cookie and token, so a copy of the old session cookie is no
+    // longer the current one
+    session.renew();
+    rotate_csrf_token(&session);
+
+    Ok(HttpResponse::Found()
+        .insert_header(("location", "/profile?msg=password_changed"))
+        .finish())
+}
+
+pub async fn profile_update_submit(
+    tmpl: web::Data<Tera>,
+    form: web::Form<ProfileUpdateForm>,
+    user: AuthUser,
+    session: Session,
+    db: web::Data<Database>,
+) -> Result<HttpResponse> {
+    verify_csrf(&session, &form.csrf_token)?;
+
+    let form = form.normalized();
+    let error = match form.validate() {
+        Err(validation_errors) => Some(validation_errors.join(", ")),
+        Ok(()) => db
+            .update_personal_info(
+                &user.0.id,
+                form.first_name.as_deref(),
+                form.last_name.as_deref(),
+                form.display_name.as_deref(),
+                form.timezone(),
+            )
+            .await
+            .err()
+            .map(|e| format!("Could not update profile: {}", e)),
+    };
+
+    if let Some(error) = error {
+        let mut ctx = profile_context(&user.0, &session, &db).await?;
+        ctx.insert("error", &error);
+        return Ok(render_profile(&tmpl, &ctx));
+    }
+
+    // Keep the name shown in the navigation, and the timezone used for
+    // displaying times, in sync
+    if let Ok(Some(account)) = db.get_user_by_id(&user.0.id).await {
+        let mut session_user = user.0;
+        session_user.display_name = account.display_name();
+        session_user.timezone = account.timezone;
+        update_session_user(&session, session_user)?;
+    }
+
+    Ok(HttpResponse::Found()
+        .insert_header(("location", "/profile?msg=details_updated"))
+        .finish())
+}
+
+/// `PATCH /api/me`: applies the profile fields present in the JSON body,
+/// leaving the rest alone, and returns the updated account. Like the other
+/// cookie-authenticated API writes it needs the `X-CSRF-Token` header.
+pub async fn api_update_me(
+    req: HttpRequest,
+    user: OptionalAuthUser,
+    patch: web::Json<ProfilePatch>,
+    session: Session,
+    db: web::Data<Database>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let Some(session_user) = user.0 else {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "unauthenticated", "Not logged in").into());
+    };
+    verify_csrf_header(&req, &session)?;
+
+    let patch = patch.normalized();
+    patch.validate().map_err(|errors| {
+        ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "validation_failed", errors.join(", "))
+    })?;
+
+    db.update_user(&session_user.id, &patch).await.map_err(ApiError::internal)?;
+    let account = db
+        .get_user_by_id(&session_user.id)
+        .await
+        .map_err(ApiError::internal)?
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "not_found", "Account not found"))?;
+
+    // Keep the name and avatar shown in the navigation in sync
+    let mut session_user = session_user;
+    session_user.display_name = account.display_name();
+    session_user.avatar_url = account.avatar_or_gravatar(config.gravatar_style.as_deref());
+    update_session_user(&session, session_user).map_err(ApiError::internal)?;
+
+    Ok(HttpResponse::Ok().json(PublicUser::from(account)))
+}
+
+pub async fn public_profile_submit(
+    tmpl: web::Data<Tera>,
+    form: web::Form<PublicProfileForm>,
+    user: AuthUser,
+    session: Session,
+    db: web::Data<Database>,
+) -> Result<HttpResponse> {
+    verify_csrf(&session, &form.csrf_token)?;
+
+    let error = match form.validate() {
+        Err(validation_errors) => Some(validation_errors.join(", ")),
+        Ok(()) => db
+            .update_public_profile(&user.0.id, form.bio().as_deref(), form.profile_public.unwrap_or(false))
+            .await
+            .err()
+            .map(|e| format!("Could not update profile: {}", e)),
+    };
+
+    if let Some(error) = error {
+        let mut ctx = profile_context(&user.0, &session, &db).await?;
+        ctx.insert("error", &error);
+        return Ok(render_profile(&tmpl, &ctx));
+    }
+
+    Ok(HttpResponse::Found()
+        .insert_header(("location", "/profile?msg=profile_updated"))
+        .finish())
+}
+
+/// `/u/{username}`: a user's public profile. Missing users and private
+/// profiles both return 404 so the page doesn't reveal which usernames exist.
+pub async fn public_profile_page(
+    tmpl: web::Data<Tera>,
+    username: web::Path<String>,
+    user: OptionalAuthUser,
+    session: Session,
+    db: web::Data<Database>,
+) -> Result<HttpResponse> {
+    // With the public_profiles flag off, every profile counts as private
+    let enabled = db
+        .is_feature_enabled("public_profiles")
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    let profile = db
+        .get_user_by_username(&username)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .filter(|account| enabled && account.profile_public)
+        .map(UserSummary::from);
+
+    let Some(profile) = profile else {
+        return Ok(HttpResponse::NotFound()
+            .content_type("text/plain; charset=utf-8")
+            .body("Profile not found"));
+    };
+
+    let mut ctx = Context::new();
+    ctx.insert("title", &profile.display_name);
+    ctx.insert("brand_name", "Rust Web AI");
+    ctx.insert("profile", &profile);
+    inject_auth(&mut ctx, &user, &session);
+
+    let body = tmpl
+        .render("public-profile.html.tera", &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+
+    Ok(html_response(body))
 }
 
 pub async fn deepseek_page(
     tmpl: web::Data<Tera>,
     user: OptionalAuthUser,
+    session: Session,
 ) -> Result<HttpResponse> {
     let mut ctx = Context::new();
     ctx.insert("title", "Deepseek AI");
     ctx.insert("brand_name", "Rust Web AI");
     ctx.insert("active", "deepseek");
     
-    if let Some(user) = user.0 {
-        ctx.insert("user", &user);
-    }
+    inject_auth(&mut ctx, &user, &session);
 
     let body = tmpl
         .render("deepseek.html.tera", &ctx)
         .unwrap_or_else(|e| format!("Template error: {e}"));
     
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(body))
+    Ok(html_response(body))
 }
 
 #[derive(serde::Deserialize)]
@@ -285,6 +672,7 @@ pub async fn deepseek_submit(
     tmpl: web::Data<Tera>,
     form: web::Form<DeepseekQuery>,
     user: OptionalAuthUser,
+    session: Session,
 ) -> Result<HttpResponse> {
     let mut ctx = Context::new();
     ctx.insert("title", "Deepseek AI");
@@ -292,9 +680,7 @@ pub async fn deepseek_submit(
     ctx.insert("active", "deepseek");
     ctx.insert("query", &form.query);
     
-    if let Some(user) = user.0 {
-        ctx.insert("user", &user);
-    }
+    inject_auth(&mut ctx, &user, &session);
 
     // For now, just echo back the query - API integration will be added later
     ctx.insert("response", &format!("You asked: '{}'", form.query));
@@ -303,7 +689,531 @@ pub async fn deepseek_submit(
         .render("deepseek.html.tera", &ctx)
         .unwrap_or_else(|e| format!("Template error: {e}"));
     
-    Ok(HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(body))
-}
\ No newline at end of file
+    Ok(html_response(body))
+}
+
+pub async fn contact_page(
+    tmpl: web::Data<Tera>,
+    query: web::Query<QueryMessage>,
+    user: OptionalAuthUser,
+    session: Session,
+) -> Result<HttpResponse> {
+    let mut ctx = Context::new();
+    ctx.insert("title", "Contact Us");
+    ctx.insert("brand_name", "Rust Web AI");
+    ctx.insert("active", "contact");
+
+    if let Some(notice) = query.notice() {
+        ctx.insert("notice", &notice);
+    }
+
+    inject_auth(&mut ctx, &user, &session);
+    with_csrf(&mut ctx, &session)?;
+
+    let body = tmpl
+        .render("contact.html.tera", &ctx)
+        .unwrap_or_else(|e| format!("Template error: {e}"));
+
+    Ok(html_response(body))
+}
+
+pub async fn contact_submit(
+    req: HttpRequest,
+    tmpl: web::Data<Tera>,
+    form: web::Form<ContactForm>,
+    session: Session,
+    db: web::Data<Database>,
+    limit: web::Data<RateLimit>,
+) -> Result<HttpResponse> {
+    verify_csrf(&session, &form.csrf_token)?;
+
+    // Bots that fill the honeypot get the normal success redirect, so they
+    // can't tell they were filtered, but nothing is stored.
+    if form.is_spam() {
+        return Ok(HttpResponse::Found()
+            .insert_header(("location", "/contact?msg=message_sent"))
+            .finish());
+    }
+
+    let mut ctx = Context::new();
+    ctx.insert("title", "Contact Us");
+    ctx.insert("brand_name", "Rust Web AI");
+    ctx.insert("active", "contact");
+    ctx.insert("form_data", &*form);
+    with_csrf(&mut ctx, &session)?;
+
+    if let Err(validation_errors) = form.validate() {
+        ctx.insert("errors", &validation_errors);
+        let body = tmpl
+            .render("contact.html.tera", &ctx)
+            .unwrap_or_else(|e| format!("Template error: {e}"));
+        return Ok(html_response(body));
+    }
+
+    if let Err(retry_after) = limit.allow(req.peer_addr().map(|addr| addr.ip())) {
+        ctx.insert("errors", &vec!["You've sent several messages recently. Please try again later."]);
+        let body = tmpl
+            .render("contact.html.tera", &ctx)
+            .unwrap_or_else(|e| format!("Template error: {e}"));
+        let mut response = html_response(body);
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        // Whole seconds, rounded up so a client retrying on time gets in
+        let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+        response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(seconds));
+        return Ok(response);
+    }
+
+    match db.save_contact_message(&form).await {
+        Ok(()) => Ok(HttpResponse::Found()
+            .insert_header(("location", "/contact?msg=message_sent"))
+            .finish()),
+        Err(e) => {
+            ctx.insert("errors", &vec![format!("Could not send your message: {}", e)]);
+            let body = tmpl
+                .render("contact.html.tera", &ctx)
+                .unwrap_or_else(|e| format!("Template error: {e}"));
+            Ok(html_response(body))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DevRenderQuery {
+    /// Template context as a JSON object, for GET requests.
+    context: Option<String>,
+}
+
+/// Dev tool: renders any loaded template with a context supplied as JSON,
+/// either in the `context` query parameter or as the request body. Only
+/// routed when `DEV_TOOLS` is on.
+pub async fn dev_render_template(
+    tmpl: web::Data<Tera>,
+    template: web::Path<String>,
+    query: web::Query<DevRenderQuery>,
+    body: Option<web::Json<serde_json::Value>>,
+) -> Result<HttpResponse> {
+    let template = template.into_inner();
+
+    // Only names Tera already loaded, so the path can't reach other files
+    if !tmpl.get_template_names().any(|name| name == template) {
+        return Ok(HttpResponse::NotFound().body(format!("Unknown template: {}", template)));
+    }
+
+    let value = match (body, query.context.as_deref()) {
+        (Some(body), _) => body.into_inner(),
+        (None, Some(context)) => serde_json::from_str(context)
+            .map_err(|e| actix_web::error::ErrorBadRequest(format!("Invalid context JSON: {}", e)))?,
+        (None, None) => serde_json::json!({}),
+    };
+    let ctx = Context::from_value(value)
+        .map_err(|e| actix_web::error::ErrorBadRequest(format!("Context must be a JSON object: {}", e)))?;
+
+    match tmpl.render(&template, &ctx) {
+        Ok(body) => Ok(html_response(body)),
+        Err(e) => {
+            // Tera nests the useful detail (e.g. a missing variable) in the source chain
+            let mut message = e.to_string();
+            let mut source = std::error::Error::source(&e);
+            while let Some(cause) = source {
+                message.push_str(&format!(": {}", cause));
+                source = cause.source();
+            }
+            Ok(HttpResponse::UnprocessableEntity()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("Template error: {}", message)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use crate::config::Config;
+    use crate::database::Database;
+    use crate::test_support::{client, register_form, PASSWORD};
+
+    const MESSAGE: &[(&str, &str)] = &[
+        ("name", "Ada"),
+        ("email", "ada@example.com"),
+        ("message", "Hello there"),
+    ];
+
+    async fn stored_messages(db: &Database) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM contact_messages")
+            .fetch_one(db.pool())
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn login_uses_the_configured_default_redirect() {
+        let config = Config { default_login_redirect: "/profile".to_string(), ..Config::for_tests() };
+        let mut client = client(config).await;
+        client.register("ada@example.com", "ada").await;
+
+        let reply = client.log_in("ada@example.com", PASSWORD).await;
+        assert_eq!(reply.location(), "/profile");
+        // Already logged in, the login and register pages forward there too
+        assert_eq!(client.get("/login").await.location(), "/profile");
+        assert_eq!(client.get("/register").await.location(), "/profile");
+
+        client.clear_cookies();
+        let login = [("email", "ada@example.com"), ("password", PASSWORD), ("next", "/contact")];
+        let reply = client.submit("/login", "/login", &login).await;
+        assert_eq!(reply.location(), "/contact");
+
+        client.clear_cookies();
+        let login = [("email", "ada@example.com"), ("password", PASSWORD), ("next", "https://evil.example")];
+        let reply = client.submit("/login", "/login", &login).await;
+        assert_eq!(reply.location(), "/profile");
+    }
+
+    #[actix_web::test]
+    async fn failed_logins_rerender_with_usable_tokens() {
+        let mut client = client(Config::for_tests()).await;
+        client.register("ada@example.com", "ada").await;
+
+        let mut token = client.get("/login").await.csrf_token();
+        for _ in 0..2 {
+            let attempt = [("email", "ada@example.com"), ("password", "typo"), ("csrf_token", &token)];
+            let reply = client.post_form("/login", &attempt).await;
+            assert_eq!(reply.status, StatusCode::OK);
+            assert!(reply.body.contains("Invalid email or password"));
+            token = reply.csrf_token();
+        }
+
+        let login = [("email", "ada@example.com"), ("password", PASSWORD), ("csrf_token", &token)];
+        let reply = client.post_form("/login", &login).await;
+        assert_eq!(reply.location(), "/dashboard");
+    }
+
+    #[actix_web::test]
+    async fn sensitive_actions_need_the_password_again() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+
+        let rename = [("username", "countess")];
+        let reply = client.submit("/profile", "/profile/username", &rename).await;
+        assert_eq!(reply.location(), "/account/verify-password?next=/profile");
+
+        let wrong = [("password", "wrong"), ("next", "/profile")];
+        let reply = client.submit("/account/verify-password", "/account/verify-password", &wrong).await;
+        assert!(reply.body.contains("Incorrect password"));
+        let reply = client.submit("/profile", "/profile/username", &rename).await;
+        assert_eq!(reply.location(), "/account/verify-password?next=/profile");
+
+        let right = [("password", PASSWORD), ("next", "/profile")];
+        let reply = client.submit("/account/verify-password", "/account/verify-password", &right).await;
+        assert_eq!(reply.location(), "/profile");
+        let reply = client.submit("/profile", "/profile/username", &rename).await;
+        assert_eq!(reply.location(), "/profile?msg=username_changed");
+    }
+
+    #[actix_web::test]
+    async fn refreshing_the_session_changes_the_cookie_but_not_the_user() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+        let before = client.session_cookie().unwrap().value().to_string();
+
+        let reply = client.submit("/profile", "/account/refresh-session", &[]).await;
+        assert_eq!(reply.location(), "/profile?msg=session_refreshed");
+        assert_ne!(client.session_cookie().unwrap().value(), before);
+
+        let reply = client.get("/profile?msg=session_refreshed").await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.body.contains("ada@example.com"));
+        assert!(reply.body.contains("Your session has been refreshed."));
+    }
+
+    #[actix_web::test]
+    async fn get_logout_only_asks_for_confirmation() {
+        let mut client = client(Config::for_tests()).await;
+        assert_eq!(client.get("/logout").await.location(), "/login");
+
+        client.sign_up("ada@example.com", "ada").await;
+        let reply = client.get("/logout").await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.body.contains("Log out?"));
+        assert_eq!(client.get("/dashboard").await.status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn logout_with_a_bad_token_falls_back_to_confirming() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+
+        for logout in [
+            test::TestRequest::post().uri("/logout"),
+            test::TestRequest::post().uri("/logout").set_form([("csrf_token", "stale")]),
+        ] {
+            let reply = client.send(logout).await;
+            assert_eq!(reply.status, StatusCode::OK);
+            assert!(reply.body.contains("Log out?"));
+            assert_eq!(client.get("/dashboard").await.status, StatusCode::OK);
+        }
+
+        let confirm = client.post_form("/logout", &[("csrf_token", "stale")]).await;
+        let reply = client.post_form("/logout", &[("csrf_token", &confirm.csrf_token())]).await;
+        assert_eq!(reply.location(), "/login?msg=logged_out");
+        assert_eq!(client.get("/dashboard").await.status, StatusCode::UNAUTHORIZED);
+
+        // Nothing to protect once logged out
+        let reply = client.send(test::TestRequest::post().uri("/logout")).await;
+        assert_eq!(reply.location(), "/login?msg=logged_out");
+    }
+
+    #[actix_web::test]
+    async fn changed_password_is_the_only_one_that_logs_in() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+
+        let wrong = [("current_password", "wrong"), ("new_password", "hunter22"), ("new_password_confirm", "hunter22")];
+        let reply = client.submit("/profile", "/profile/password", &wrong).await;
+        assert!(reply.body.contains("Current password is incorrect"));
+
+        let change = [("current_password", PASSWORD), ("new_password", "hunter22"), ("new_password_confirm", "hunter22")];
+        let reply = client.submit("/profile", "/profile/password", &change).await;
+        assert_eq!(reply.location(), "/profile?msg=password_changed");
+
+        client.clear_cookies();
+        assert!(client.log_in("ada@example.com", PASSWORD).await.body.contains("Invalid email or password"));
+        assert_eq!(client.log_in("ada@example.com", "hunter22").await.location(), "/dashboard");
+    }
+
+    #[actix_web::test]
+    async fn remember_me_makes_the_session_cookie_persistent() {
+        let mut client = client(Config::for_tests()).await;
+        client.register("ada@example.com", "ada").await;
+
+        client.log_in("ada@example.com", PASSWORD).await;
+        let cookie = client.session_cookie().unwrap();
+        assert_eq!(cookie.max_age(), None);
+
+        client.clear_cookies();
+        let login = [("email", "ada@example.com"), ("password", PASSWORD), ("remember_me", "true")];
+        client.submit("/login", "/login", &login).await;
+        let cookie = client.session_cookie().unwrap();
+        assert_eq!(cookie.max_age(), Some(actix_web::cookie::time::Duration::days(30)));
+        // Later responses keep the cookie persistent
+        client.get("/dashboard").await;
+        assert_eq!(client.session_cookie().unwrap().max_age(), Some(actix_web::cookie::time::Duration::days(30)));
+    }
+
+    #[actix_web::test]
+    async fn dev_render_renders_loaded_templates_only() {
+        let config = Config { dev_tools: true, ..Config::for_tests() };
+        let mut client = client(config).await;
+
+        let render = test::TestRequest::post()
+            .uri("/dev/render/errors/csrf.html.tera")
+            .set_json(serde_json::json!({ "brand_name": "Acme", "back_url": "retry" }));
+        let reply = client.send(render).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.body.contains("Please Try Again - Acme"));
+        assert!(reply.body.contains(r#"href="retry""#));
+
+        for unknown in ["/dev/render/missing.html.tera", "/dev/render/../Cargo.toml", "/dev/render/%2e%2e/Cargo.toml"] {
+            let reply = client.get(unknown).await;
+            assert_eq!(reply.status, StatusCode::NOT_FOUND, "{unknown}");
+            assert!(!reply.body.contains("[package]"), "{unknown}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn dev_render_is_off_by_default() {
+        let mut client = client(Config::for_tests()).await;
+        let reply = client.get("/dev/render/errors/csrf.html.tera").await;
+        assert_eq!(reply.status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn public_profiles_render_and_private_ones_404() {
+        let mut client = client(Config::for_tests()).await;
+        let ada = client.db.create_user(register_form("ada@example.com", "ada")).await.unwrap();
+        client.db.create_user(register_form("grace@example.com", "grace")).await.unwrap();
+        client.db.update_public_profile(&ada.id, Some("First programmer"), true).await.unwrap();
+
+        let reply = client.get("/u/ada").await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.body.contains("First programmer"));
+        assert!(!reply.body.contains("ada@example.com"));
+        assert!(!reply.body.contains("$2b$"));
+
+        assert_eq!(client.get("/u/grace").await.status, StatusCode::NOT_FOUND);
+        assert_eq!(client.get("/u/nobody").await.status, StatusCode::NOT_FOUND);
+
+        client.db.set_feature_flag("public_profiles", false).await.unwrap();
+        assert_eq!(client.get("/u/ada").await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn api_me_applies_only_provided_fields() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+        let token = client.get("/dashboard").await.csrf_token();
+        let patch = |body: serde_json::Value| {
+            test::TestRequest::patch()
+                .uri("/api/me")
+                .insert_header(("X-CSRF-Token", token.clone()))
+                .set_json(body)
+        };
+
+        let unsigned = test::TestRequest::patch().uri("/api/me").set_json(serde_json::json!({ "bio": "Forged" }));
+        let reply = client.send(unsigned).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+        assert!(reply.body.contains("csrf_failed"));
+        assert!(!client.get("/profile").await.body.contains("Forged"));
+
+        let reply = client.send(patch(serde_json::json!({ "first_name": "Ada", "bio": "Analyst" }))).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let account: serde_json::Value = serde_json::from_str(&reply.body).unwrap();
+        assert_eq!(account["first_name"], "Ada");
+        assert_eq!(account["bio"], "Analyst");
+
+        let reply = client.send(patch(serde_json::json!({ "bio": null }))).await;
+        let account: serde_json::Value = serde_json::from_str(&reply.body).unwrap();
+        assert_eq!(account["first_name"], "Ada");
+        assert_eq!(account["bio"], serde_json::Value::Null);
+
+        let reply = client.send(patch(serde_json::json!({ "avatar_url": "javascript:alert(1)" }))).await;
+        assert_eq!(reply.status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(reply.body.contains("validation_failed"));
+
+        client.clear_cookies();
+        let reply = client.send(patch(serde_json::json!({ "bio": "x" }))).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn padded_email_logs_into_the_unpadded_account() {
+        let mut client = client(Config::for_tests()).await;
+        client.register("ada@example.com", "ada").await;
+
+        let reply = client.log_in(" ada@example.com  ", PASSWORD).await;
+        assert_eq!(reply.status, StatusCode::FOUND);
+        assert_eq!(reply.location(), "/dashboard");
+    }
+
+    #[actix_web::test]
+    async fn username_changes_respect_the_cooldown() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+        client.sudo().await;
+
+        let reply = client.submit("/profile", "/profile/username", &[("username", "countess")]).await;
+        assert_eq!(reply.location(), "/profile?msg=username_changed");
+        assert!(client.get("/profile").await.body.contains("countess"));
+
+        let reply = client.submit("/profile", "/profile/username", &[("username", "lovelace")]).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.body.contains("You can change your username again in 30 day(s)"));
+        assert!(client.db.get_user_by_username("lovelace").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn username_changes_reject_taken_names() {
+        let mut client = client(Config::for_tests()).await;
+        client.register("grace@example.com", "grace").await;
+        client.sign_up("ada@example.com", "ada").await;
+        client.sudo().await;
+
+        let reply = client.submit("/profile", "/profile/username", &[("username", "grace")]).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.body.contains("Username is already taken"));
+        let ada = client.db.get_user_by_username("ada").await.unwrap().unwrap();
+        assert!(ada.username_changed_at.is_none());
+    }
+
+    #[actix_web::test]
+    async fn api_logout_requires_the_csrf_header() {
+        let mut client = client(Config::for_tests()).await;
+        client.sign_up("ada@example.com", "ada").await;
+        let token = client.get("/dashboard").await.csrf_token();
+
+        let reply = client.send(test::TestRequest::post().uri("/api/logout")).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+        assert!(reply.body.contains("csrf_failed"));
+        assert_eq!(client.get("/dashboard").await.status, StatusCode::OK);
+
+        let logout = test::TestRequest::post()
+            .uri("/api/logout")
+            .insert_header(("X-CSRF-Token", token));
+        let reply = client.send(logout).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body, r#"{"ok":true}"#);
+
+        let patch = test::TestRequest::patch().uri("/api/me").set_json(serde_json::json!({}));
+        assert_eq!(client.send(patch).await.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(client.get("/dashboard").await.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn pages_expose_the_csrf_token_to_scripts() {
+        let mut client = client(Config::for_tests()).await;
+
+        let page = client.get("/login").await;
+        let meta = format!(r#"<meta name="csrf-token" content="{}">"#, page.csrf_token());
+        assert!(page.body.contains(&meta));
+    }
+
+    #[actix_web::test]
+    async fn contact_stores_a_valid_message() {
+        let mut client = client(Config::for_tests()).await;
+
+        let reply = client.submit("/contact", "/contact", MESSAGE).await;
+        assert_eq!(reply.status, StatusCode::FOUND);
+        assert_eq!(reply.location(), "/contact?msg=message_sent");
+        assert_eq!(stored_messages(&client.db).await, 1);
+    }
+
+    #[actix_web::test]
+    async fn contact_rerenders_invalid_messages() {
+        let mut client = client(Config::for_tests()).await;
+
+        let reply = client
+            .submit("/contact", "/contact", &[("name", ""), ("email", "nope"), ("message", "Hi")])
+            .await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert!(reply.body.contains("Name is required"));
+        assert!(reply.body.contains("Please enter a valid email address"));
+        assert_eq!(stored_messages(&client.db).await, 0);
+    }
+
+    #[actix_web::test]
+    async fn contact_honeypot_pretends_to_succeed() {
+        let mut client = client(Config::for_tests()).await;
+
+        let mut form = MESSAGE.to_vec();
+        form.push(("website", "http://spam.example"));
+        let reply = client.submit("/contact", "/contact", &form).await;
+        assert_eq!(reply.location(), "/contact?msg=message_sent");
+        assert_eq!(stored_messages(&client.db).await, 0);
+    }
+
+    #[actix_web::test]
+    async fn contact_is_rate_limited() {
+        let config = Config { contact_rate_limit: 2, ..Config::for_tests() };
+        let mut client = client(config).await;
+
+        for _ in 0..2 {
+            let reply = client.submit("/contact", "/contact", MESSAGE).await;
+            assert_eq!(reply.status, StatusCode::FOUND);
+        }
+        let reply = client.submit("/contact", "/contact", MESSAGE).await;
+        assert_eq!(reply.status, StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = reply.headers.get("retry-after").unwrap().to_str().unwrap().parse().unwrap();
+        assert!((3590..=3600).contains(&retry_after), "{retry_after}");
+        assert_eq!(stored_messages(&client.db).await, 2);
+    }
+
+    #[actix_web::test]
+    async fn contact_requires_a_csrf_token() {
+        let mut client = client(Config::for_tests()).await;
+
+        client.get("/contact").await;
+        let reply = client.post_form("/contact", MESSAGE).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+        assert_eq!(stored_messages(&client.db).await, 0);
+    }
+}
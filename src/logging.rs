@@ -0,0 +1,89 @@
+use actix_web::middleware::Logger;
+
+/// Access log format matching `Logger::default()`, except that the request
+/// line and referer go through `redact_query` first.
+const ACCESS_LOG_FORMAT: &str =
+    r#"%a "%{request_line}xi" %s %b "%{referer}xi" "%{User-Agent}i" %T"#;
+
+/// Builds the access logger, replacing the values of `sensitive_params` in
+/// logged URLs with `[REDACTED]` so tokens and codes don't end up in logs.
+pub fn access_logger(sensitive_params: &[String]) -> Logger {
+    let request_params = sensitive_params.to_vec();
+    let referer_params = sensitive_params.to_vec();
+
+    Logger::new(ACCESS_LOG_FORMAT)
+        .custom_request_replace("request_line", move |req| {
+            format!(
+                "{} {} {:?}",
+                req.method(),
+                redact_query(&req.uri().to_string(), &request_params),
+                req.version()
+            )
+        })
+        .custom_request_replace("referer", move |req| {
+            req.headers()
+                .get("referer")
+                .and_then(|value| value.to_str().ok())
+                .map(|referer| redact_query(referer, &referer_params))
+                .unwrap_or_else(|| "-".to_string())
+        })
+}
+
+/// Replaces the value of each query parameter named in `sensitive` (compared
+/// case-insensitively) with `[REDACTED]`, leaving the rest of the URL intact.
+pub fn redact_query(url: &str, sensitive: &[String]) -> String {
+    let Some((path, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+
+    let pairs: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if sensitive.iter().any(|s| s.eq_ignore_ascii_case(key)) => {
+                format!("{}=[REDACTED]", key)
+            }
+            _ => pair.to_string(),
+        })
+        .collect();
+
+    format!("{}?{}", path, pairs.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support::{capture_logs, client, logged};
+
+    fn sensitive() -> Vec<String> {
+        ["token", "code", "password"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn redact_query_replaces_only_sensitive_values() {
+        assert_eq!(
+            redact_query("/reset-password?token=abc123&lang=en", &sensitive()),
+            "/reset-password?token=[REDACTED]&lang=en"
+        );
+        assert_eq!(
+            redact_query("https://example.com/cb?state=xyz&CODE=42", &sensitive()),
+            "https://example.com/cb?state=xyz&CODE=[REDACTED]"
+        );
+        assert_eq!(redact_query("/login?next=/profile", &sensitive()), "/login?next=/profile");
+        assert_eq!(redact_query("/dashboard", &sensitive()), "/dashboard");
+        // A name that merely contains a sensitive one is left alone
+        assert_eq!(redact_query("/x?tokens=1&token", &sensitive()), "/x?tokens=1&token");
+    }
+
+    #[actix_web::test]
+    async fn access_log_redacts_reset_tokens() {
+        capture_logs();
+        let mut client = client(Config::for_tests()).await;
+
+        client.get("/reset-password?token=s3cr3t-for-logging-test").await;
+
+        assert!(logged("s3cr3t-for-logging-test").is_empty());
+        let lines = logged("GET /reset-password?token=[REDACTED]");
+        assert_eq!(lines.len(), 1, "{lines:?}");
+    }
+}
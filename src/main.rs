@@ -1,30 +1,70 @@
 use actix_files::Files;
-use actix_web::{web, App, HttpResponse, HttpServer, Responder, middleware::Logger};
-use actix_session::SessionMiddleware;
+use actix_web::{guard, web, App, HttpRequest, HttpResponse, HttpServer, Responder, middleware::{from_fn, Compress, NormalizePath}};
+use actix_session::{storage::CookieSessionStore, Session, SessionMiddleware};
+use actix_web::body::MessageBody;
 use actix_web::cookie::Key;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::hint::black_box;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tera::{Context, Tera};
 
+mod api;
+mod assets;
+mod cli;
+mod compression;
+mod concurrency;
+mod config;
 mod models;
 mod database;
+mod features;
 mod auth;
+mod csrf;
+mod logging;
+mod page;
+mod query_stats;
+mod rate_limit;
+mod redirect;
 mod handlers;
+mod service;
+mod tarpit;
+mod templating;
+#[cfg(test)]
+mod test_support;
 
+use api::ApiError;
+use assets::{AssetManifest, ASSETS_PREFIX};
+use concurrency::ConcurrencyLimit;
+use config::Config;
 use database::Database;
-use auth::{logout, OptionalAuthUser};
+use rate_limit::RateLimit;
+use service::UserService;
+use tarpit::LoginTarpit;
+use templating::html_response;
+use auth::{api_logout, inject_auth, AuthUser, OptionalAuthUser};
+use csrf::verify_csrf_header;
 
-fn tera_engine() -> Tera {
+fn tera_engine(assets: web::Data<AssetManifest>) -> Tera {
     let mut tera = Tera::new("templates/**/*").expect("init tera");
     
     // Enable autoescape for security
     tera.autoescape_on(vec!["html", "tera"]);
+    templating::register(&mut tera, assets);
     
     tera
 }
 
-async fn index(tmpl: web::Data<Tera>, user: OptionalAuthUser) -> impl Responder {
+fn build_session_middleware(config: &Config, key: Key) -> SessionMiddleware<CookieSessionStore> {
+    SessionMiddleware::builder(CookieSessionStore::default(), key)
+        .cookie_name(auth::SESSION_COOKIE_NAME.to_string())
+        .cookie_same_site(config.session_same_site)
+        .cookie_secure(config.cookie_secure)
+        .build()
+}
+
+async fn index(tmpl: web::Data<Tera>, user: OptionalAuthUser, session: Session) -> impl Responder {
     let mut ctx = Context::new();
     ctx.insert("title", "Rust Web AI");
     ctx.insert("active", "home");
@@ -98,24 +138,26 @@ async fn index(tmpl: web::Data<Tera>, user: OptionalAuthUser) -> impl Responder
     ctx.insert("social_links", &social_links);
     
     // Pass user info if logged in
-    if let Some(user_data) = user.0 {
-        ctx.insert("user", &user_data);
-    }
+    inject_auth(&mut ctx, &user, &session);
     
     let body = tmpl
         .render("index.html.tera", &ctx)
         .unwrap_or_else(|e| format!("Template error: {e}"));
-    HttpResponse::Ok()
-        .content_type("text/html; charset=utf-8")
-        .body(body)
+    html_response(body)
 }
 
 #[derive(Debug, Deserialize)]
 struct BenchQuery {
     /// number of operations to run (default: 5_000_000)
     ops: Option<u64>,
+    /// include CPU core count and target triple in the output (default: false)
+    include_sysinfo: Option<bool>,
 }
 
+/// Kind recorded for `/api/bench` runs in the history. There is only the
+/// one integer-mixing workload so far.
+const BENCH_KIND: &str = "int_mix";
+
 #[derive(Debug, Serialize)]
 struct BenchOut {
     ops: u64,
@@ -123,9 +165,48 @@ struct BenchOut {
     ops_per_sec: f64,
     mops_per_sec: f64,
     acc: u64, // to ensure the loop isn't optimized away
+    // Only build/runtime facts, never host identity (hostname, IPs)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_cores: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'static str>,
+}
+
+/// Runs the benchmark without recording it, so anyone can try it and a
+/// GET never writes. Logged-in users `POST` to add a run to the history.
+async fn bench(q: web::Query<BenchQuery>, db: web::Data<Database>) -> Result<HttpResponse, ApiError> {
+    ensure_bench_enabled(&db).await?;
+    Ok(HttpResponse::Ok().json(run_bench(&q)))
+}
+
+/// Runs the benchmark and records it in the history. Needs a login and,
+/// like other cookie-authenticated API calls, the `X-CSRF-Token` header.
+async fn record_bench(
+    req: HttpRequest,
+    session: Session,
+    _user: AuthUser,
+    q: web::Query<BenchQuery>,
+    db: web::Data<Database>,
+) -> actix_web::Result<HttpResponse> {
+    ensure_bench_enabled(&db).await?;
+    verify_csrf_header(&req, &session)?;
+
+    let out = run_bench(&q);
+    db.record_bench_run(BENCH_KIND, out.ops, out.seconds, out.ops_per_sec)
+        .await
+        .map_err(ApiError::internal)?;
+
+    Ok(HttpResponse::Ok().json(out))
 }
 
-async fn bench(q: web::Query<BenchQuery>) -> impl Responder {
+async fn ensure_bench_enabled(db: &Database) -> Result<(), ApiError> {
+    if !db.is_feature_enabled("bench").await.map_err(ApiError::internal)? {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "not_found", "The benchmark is disabled"));
+    }
+    Ok(())
+}
+
+fn run_bench(q: &BenchQuery) -> BenchOut {
     let ops = q.ops.unwrap_or(5_000_000);
     let mut acc: u64 = 0;
 
@@ -141,61 +222,366 @@ async fn bench(q: web::Query<BenchQuery>) -> impl Responder {
     let dt = start.elapsed().as_secs_f64();
 
     let ops_per_sec = (ops as f64) / dt;
-    let out = BenchOut {
+    let include_sysinfo = q.include_sysinfo.unwrap_or(false);
+    BenchOut {
         ops,
         seconds: dt,
         ops_per_sec,
         mops_per_sec: ops_per_sec / 1_000_000.0,
         acc,
-    };
+        cpu_cores: include_sysinfo
+            .then(|| std::thread::available_parallelism().map(|n| n.get()).ok())
+            .flatten(),
+        target: include_sysinfo.then_some(env!("TARGET_TRIPLE")),
+    }
+}
+
+#[derive(Deserialize)]
+struct BenchHistoryQuery {
+    /// number of most recent runs to return (default: 20, max: 1000)
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct BenchAggregate {
+    runs: usize,
+    best_ops_per_sec: f64,
+    median_ops_per_sec: f64,
+}
+
+/// The most recent `/api/bench` runs, newest first, with the best and
+/// median throughput per benchmark kind over those runs.
+async fn bench_history(q: web::Query<BenchHistoryQuery>, db: web::Data<Database>) -> Result<HttpResponse, ApiError> {
+    ensure_bench_enabled(&db).await?;
+
+    let limit = q.limit.unwrap_or(20).clamp(1, 1000);
+    let runs = db.recent_bench_runs(limit).await.map_err(ApiError::internal)?;
+
+    let mut by_kind: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+    for run in &runs {
+        by_kind.entry(run.kind.as_str()).or_default().push(run.ops_per_sec);
+    }
+    let aggregates: BTreeMap<&str, BenchAggregate> = by_kind
+        .into_iter()
+        .map(|(kind, mut rates)| {
+            rates.sort_by(f64::total_cmp);
+            let mid = rates.len() / 2;
+            let median = if rates.len() % 2 == 0 {
+                (rates[mid - 1] + rates[mid]) / 2.0
+            } else {
+                rates[mid]
+            };
+            let aggregate = BenchAggregate {
+                runs: rates.len(),
+                best_ops_per_sec: rates[rates.len() - 1],
+                median_ops_per_sec: median,
+            };
+            (kind, aggregate)
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "runs": runs, "aggregates": aggregates })))
+}
+
+async fn health(db: web::Data<Database>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "pool": db.pool_status(),
+    }))
+}
+
+/// Everything the app shares across workers. Built once, outside the
+/// `HttpServer::new` closure, so limits and caches apply per process.
+#[derive(Clone)]
+struct AppState {
+    config: Config,
+    database: Database,
+    user_service: UserService,
+    tera: Tera,
+    assets: web::Data<AssetManifest>,
+    // Session encryption key, derived from SESSION_SECRET when set
+    secret_key: Key,
+    concurrency_limit: ConcurrencyLimit,
+    login_tarpit: LoginTarpit,
+    contact_limit: RateLimit,
+}
+
+impl AppState {
+    fn new(config: Config, database: Database, tera: Tera, assets: web::Data<AssetManifest>) -> Self {
+        Self {
+            user_service: UserService::new(database.clone()),
+            secret_key: config.session_key(),
+            concurrency_limit: ConcurrencyLimit::new(config.max_concurrent_requests),
+            login_tarpit: LoginTarpit::new(
+                Duration::from_millis(config.login_tarpit_ms),
+                Duration::from_millis(config.login_tarpit_max_ms),
+            ),
+            contact_limit: RateLimit::new(config.contact_rate_limit, Duration::from_secs(60 * 60)),
+            config,
+            database,
+            tera,
+            assets,
+        }
+    }
+}
+
+/// The application with all middleware and routes, as each worker runs it.
+fn build_app(
+    state: AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let config = &state.config;
+    let assets = state.assets.clone();
+
+    App::new()
+        .app_data(web::Data::new(state.tera))
+        .app_data(web::Data::new(state.database))
+        .app_data(web::Data::new(state.user_service))
+        .app_data(web::Data::new(config.clone()))
+        .app_data(web::Data::new(state.contact_limit))
+        .app_data(web::Data::new(state.concurrency_limit))
+        .app_data(web::Data::new(state.login_tarpit))
+        .app_data(assets.clone())
+        .wrap(from_fn(csrf::csrf_failure_page))
+        .wrap(from_fn(query_stats::track_queries))
+        // Directly inside Compress, which it tells to skip small responses
+        .wrap(from_fn(compression::skip_compression))
+        .wrap(Compress::default())
+        .wrap(logging::access_logger(&config.log_redact_params))
+        .wrap(build_session_middleware(config, state.secret_key))
+        // Outside the session middleware so it sees the cookie it sets
+        .wrap(from_fn(auth::persist_remembered_session))
+        .wrap(NormalizePath::new(config.trailing_slash))
+        // Outermost, so shed requests skip session decoding and routing
+        .wrap(from_fn(concurrency::limit_concurrency))
+        // Content-hashed asset URLs first; anything else under /assets
+        // falls through to the plain file service
+        .service(
+            web::resource(format!("{}/{{path:.*}}", ASSETS_PREFIX))
+                .guard(guard::fn_guard({
+                    let assets = assets.clone();
+                    move |ctx| assets.file_for(ctx.head().uri.path()).is_some()
+                }))
+                .to(assets::serve_hashed),
+        )
+        .service(Files::new(ASSETS_PREFIX, config.static_dir()).prefer_utf8(true))
+        // Public routes
+        .route("/", web::get().to(index))
+        .route("/health", web::get().to(health))
+        .route("/u/{username}", web::get().to(handlers::public_profile_page))
 
-    HttpResponse::Ok().json(out)
+        // JSON API
+        .service(
+            web::scope("/api")
+                .app_data(api::json_config())
+                .route("/bench", web::get().to(bench))
+                .route("/bench", web::post().to(record_bench))
+                .route("/bench/history", web::get().to(bench_history))
+                .route("/me", web::patch().to(handlers::api_update_me))
+                .route("/logout", web::post().to(api_logout)),
+        )
+        
+        // Authentication routes
+        .route("/login", web::get().to(handlers::login_page))
+        .route("/login", web::post().to(handlers::login_submit))
+        .route("/register", web::get().to(handlers::register_page))
+        .route("/register", web::post().to(handlers::register_submit))
+        .route("/logout", web::get().to(handlers::logout_page))
+        .route("/logout", web::post().to(handlers::logout_submit))
+        
+        // AI routes (accessible to all users)
+        .route("/deepseek", web::get().to(handlers::deepseek_page))
+        .route("/deepseek", web::post().to(handlers::deepseek_submit))
+        
+        // Contact form
+        .route("/contact", web::get().to(handlers::contact_page))
+        .route("/contact", web::post().to(handlers::contact_submit))
+        
+        // Protected routes
+        .route("/dashboard", web::get().to(handlers::dashboard_page))
+        .route("/profile", web::get().to(handlers::profile_page))
+        .route("/profile/update", web::post().to(handlers::profile_update_submit))
+        .route("/profile/username", web::post().to(handlers::change_username_submit))
+        .route("/profile/password", web::post().to(handlers::change_password_submit))
+        .route("/profile/public", web::post().to(handlers::public_profile_submit))
+        .route("/account/verify-password", web::get().to(handlers::verify_password_page))
+        .route("/account/verify-password", web::post().to(handlers::verify_password_submit))
+        .route("/account/refresh-session", web::post().to(handlers::refresh_session_submit))
+
+        // Developer tools, only when DEV_TOOLS is on outside production
+        .configure(|cfg| {
+            if config.dev_tools {
+                cfg.route("/dev/render/{template:.*}", web::get().to(handlers::dev_render_template))
+                    .route("/dev/render/{template:.*}", web::post().to(handlers::dev_render_template));
+            }
+        })
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
     
-    let tera = tera_engine();
+    let config = Config::from_env().expect("Invalid configuration");
+    if let Err(e) = config.validate_for_env().and_then(|_| config.check_static_dir()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    templating::set_minify_html(config.minify_html);
+    let assets = web::Data::new(AssetManifest::build(config.static_dir()));
+    let tera = tera_engine(assets.clone());
     
     // Initialize database
-    let database = Database::new().await.expect("Failed to initialize database");
-    
-    // Generate a random key for session encryption
-    // In production, use a persistent key from environment variable
-    let secret_key = Key::generate();
-
-    let server = HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(tera.clone()))
-            .app_data(web::Data::new(database.clone()))
-            .wrap(Logger::default())
-            .wrap(SessionMiddleware::new(
-                actix_session::storage::CookieSessionStore::default(),
-                secret_key.clone()
-            ))
-            .service(Files::new("/assets", "static/assets").prefer_utf8(true))
-            // Public routes
-            .route("/", web::get().to(index))
-            .route("/api/bench", web::get().to(bench))
-            
-            // Authentication routes
-            .route("/login", web::get().to(handlers::login_page))
-            .route("/login", web::post().to(handlers::login_submit))
-            .route("/register", web::get().to(handlers::register_page))
-            .route("/register", web::post().to(handlers::register_submit))
-            .route("/logout", web::post().to(logout))
-            
-            // AI routes (accessible to all users)
-            .route("/deepseek", web::get().to(handlers::deepseek_page))
-            .route("/deepseek", web::post().to(handlers::deepseek_submit))
-            
-            // Protected routes
-            .route("/dashboard", web::get().to(handlers::dashboard_page))
-            .route("/profile", web::get().to(handlers::profile_page))
-    })
-    .bind("127.0.0.1:8080")?;
+    let database = Database::new(&config).await.expect("Failed to initialize database");
+
+    // Maintenance subcommands run against the database and exit
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        if let Err(e) = cli::run(&args, &config, &database).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let state = AppState::new(config, database, tera, assets);
+    let server = HttpServer::new(move || build_app(state.clone())).bind("127.0.0.1:8080")?;
     
     println!("🚀 Server running on http://127.0.0.1:8080");
     server.run().await
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::cookie::SameSite;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::TrailingSlash;
+    use serde_json::Value;
+    use actix_web::test::TestRequest;
+    use crate::config::Config;
+    use crate::test_support::client;
+
+    #[actix_web::test]
+    async fn index_branches_on_is_authenticated() {
+        let mut client = client(Config::for_tests()).await;
+
+        let anonymous = client.get("/").await.body;
+        assert!(anonymous.contains("Sign In"));
+        assert!(!anonymous.contains("Go to Dashboard"));
+
+        client.sign_up("ada@example.com", "ada").await;
+        let logged_in = client.get("/").await.body;
+        assert!(logged_in.contains("Go to Dashboard"));
+        assert!(!logged_in.contains("Sign In"));
+    }
+
+    #[actix_web::test]
+    async fn bench_sysinfo_only_when_requested() {
+        let mut client = client(Config::for_tests()).await;
+        client.db.set_feature_flag("bench", true).await.unwrap();
+
+        let lean: Value = serde_json::from_str(&client.get("/api/bench?ops=1000").await.body).unwrap();
+        assert_eq!(lean["ops"], 1000);
+        assert!(lean.get("cpu_cores").is_none());
+        assert!(lean.get("target").is_none());
+
+        let reply = client.get("/api/bench?ops=1000&include_sysinfo=true").await;
+        let full: Value = serde_json::from_str(&reply.body).unwrap();
+        assert!(full["cpu_cores"].as_u64().is_some_and(|cores| cores >= 1));
+        assert_eq!(full["target"], env!("TARGET_TRIPLE"));
+    }
+
+    #[actix_web::test]
+    async fn only_logged_in_posts_record_bench_runs() {
+        let mut client = client(Config::for_tests()).await;
+        assert_eq!(client.get("/api/bench?ops=1000").await.status, StatusCode::OK);
+        let reply = client.send(TestRequest::post().uri("/api/bench?ops=1000")).await;
+        assert_eq!(reply.status, StatusCode::UNAUTHORIZED);
+        assert!(client.db.recent_bench_runs(10).await.unwrap().is_empty());
+
+        client.sign_up("ada@example.com", "ada").await;
+        let reply = client.send(TestRequest::post().uri("/api/bench?ops=1000")).await;
+        assert_eq!(reply.status, StatusCode::FORBIDDEN);
+        assert!(client.db.recent_bench_runs(10).await.unwrap().is_empty());
+
+        let token = client.get("/dashboard").await.csrf_token();
+        let post = TestRequest::post()
+            .uri("/api/bench?ops=1000")
+            .insert_header(("X-CSRF-Token", token));
+        let reply = client.send(post).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        let run: Value = serde_json::from_str(&reply.body).unwrap();
+        assert_eq!(run["ops"], 1000);
+
+        let history: Value = serde_json::from_str(&client.get("/api/bench/history").await.body).unwrap();
+        assert_eq!(history["runs"].as_array().unwrap().len(), 1);
+        assert_eq!(history["runs"][0]["ops"], 1000);
+        assert_eq!(history["runs"][0]["ops_per_sec"], run["ops_per_sec"]);
+    }
+
+    #[actix_web::test]
+    async fn bench_history_aggregates_per_kind() {
+        let mut client = client(Config::for_tests()).await;
+        for (kind, ops_per_sec) in [("int_mix", 300.0), ("int_mix", 100.0), ("int_mix", 200.0), ("float", 50.0), ("float", 70.0)] {
+            client.db.record_bench_run(kind, 1000, 1.0, ops_per_sec).await.unwrap();
+        }
+
+        let history: Value = serde_json::from_str(&client.get("/api/bench/history").await.body).unwrap();
+        assert_eq!(history["runs"].as_array().unwrap().len(), 5);
+        assert_eq!(history["runs"][0]["kind"], "float");
+        assert_eq!(history["aggregates"]["int_mix"]["runs"], 3);
+        assert_eq!(history["aggregates"]["int_mix"]["best_ops_per_sec"], 300.0);
+        assert_eq!(history["aggregates"]["int_mix"]["median_ops_per_sec"], 200.0);
+        assert_eq!(history["aggregates"]["float"]["median_ops_per_sec"], 60.0);
+
+        // Aggregates cover only the runs returned
+        let history: Value = serde_json::from_str(&client.get("/api/bench/history?limit=2").await.body).unwrap();
+        assert!(history["aggregates"].get("int_mix").is_none());
+        assert_eq!(history["aggregates"]["float"]["runs"], 2);
+    }
+
+    #[actix_web::test]
+    async fn trailing_slashes_reach_the_same_page() {
+        let mut client = client(Config::for_tests()).await;
+
+        let plain = client.get("/login").await;
+        let slashed = client.get("/login/").await;
+        assert_eq!(plain.status, StatusCode::OK);
+        assert_eq!(slashed.status, StatusCode::OK);
+        assert_eq!(plain.body, slashed.body);
+        assert_eq!(client.get("//login//").await.body, plain.body);
+    }
+
+    #[actix_web::test]
+    async fn merge_only_keeps_a_single_trailing_slash() {
+        let config = Config { trailing_slash: TrailingSlash::MergeOnly, ..Config::for_tests() };
+        let mut client = client(config).await;
+
+        assert_eq!(client.get("//login").await.status, StatusCode::OK);
+        assert_eq!(client.get("/login//").await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn session_cookie_carries_the_configured_same_site() {
+        for (same_site, attribute) in [
+            (SameSite::Lax, "SameSite=Lax"),
+            (SameSite::Strict, "SameSite=Strict"),
+            (SameSite::None, "SameSite=None"),
+        ] {
+            let config = Config { session_same_site: same_site, ..Config::for_tests() };
+            let mut client = client(config).await;
+
+            let reply = client.get("/login").await;
+            let set_cookie = reply.headers.get("set-cookie").unwrap().to_str().unwrap();
+            assert!(set_cookie.contains(attribute), "{set_cookie}");
+            assert!(set_cookie.contains("Secure"), "{set_cookie}");
+        }
+    }
+}
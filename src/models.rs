@@ -1,7 +1,8 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct User {
@@ -15,6 +16,14 @@ pub struct User {
     pub is_active: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub username_changed_at: Option<DateTime<Utc>>,
+    pub login_count: i64,
+    pub last_login_at: Option<DateTime<Utc>>,
+    pub bio: Option<String>,
+    pub profile_public: bool,
+    pub display_name: Option<String>,
+    /// IANA timezone name used to display times, e.g. `Europe/Stockholm`.
+    pub timezone: String,
 }
 
 impl User {
@@ -31,11 +40,41 @@ impl User {
             is_active: true,
             created_at: now,
             updated_at: now,
+            username_changed_at: None,
+            login_count: 0,
+            last_login_at: None,
+            bio: None,
+            profile_public: false,
+            display_name: None,
+            timezone: "UTC".to_string(),
+        }
+    }
+
+    /// Returns the stored avatar URL, or a Gravatar URL for the account email
+    /// when `gravatar_style` is set (Gravatar's `d=` default image style).
+    /// Gravatar is opt-in because it reveals a hash of the email to a third party.
+    pub fn avatar_or_gravatar(&self, gravatar_style: Option<&str>) -> Option<String> {
+        if let Some(url) = &self.avatar_url {
+            return Some(url.clone());
+        }
+
+        gravatar_style.map(|style| {
+            let hash = Md5::digest(self.email.trim().to_lowercase().as_bytes());
+            format!("https://www.gravatar.com/avatar/{:x}?d={}", hash, style)
+        })
+    }
+
+    /// The name shown to people: the chosen display name, else the full
+    /// name, else the username. Blank values count as unset.
+    pub fn display_name(&self) -> String {
+        match non_blank(&self.display_name) {
+            Some(display_name) => display_name.clone(),
+            None => self.full_name(),
         }
     }
 
     pub fn full_name(&self) -> String {
-        match (&self.first_name, &self.last_name) {
+        match (non_blank(&self.first_name), non_blank(&self.last_name)) {
             (Some(first), Some(last)) => format!("{} {}", first, last),
             (Some(first), None) => first.clone(),
             (None, Some(last)) => last.clone(),
@@ -44,33 +83,123 @@ impl User {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+fn non_blank(value: &Option<String>) -> Option<&String> {
+    value.as_ref().filter(|value| !value.trim().is_empty())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SessionUser {
     pub id: String,
     pub email: String,
     pub username: String,
-    pub full_name: String,
+    pub display_name: String,
     pub avatar_url: Option<String>,
+    pub timezone: String,
 }
 
 impl From<User> for SessionUser {
     fn from(user: User) -> Self {
-        let full_name = user.full_name();
+        let display_name = user.display_name();
+        Self {
+            id: user.id,
+            email: user.email,
+            username: user.username,
+            display_name,
+            avatar_url: user.avatar_url,
+            timezone: user.timezone,
+        }
+    }
+}
+
+/// What other people may see about a user. Built from `User` so templates
+/// for public pages never have the email or password hash in their context.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserSummary {
+    pub username: String,
+    pub display_name: String,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<User> for UserSummary {
+    fn from(user: User) -> Self {
+        let display_name = user.display_name();
+        Self {
+            username: user.username,
+            display_name,
+            // Only an uploaded avatar: a Gravatar URL would publish a hash
+            // of the email address
+            avatar_url: user.avatar_url,
+            bio: user.bio,
+            created_at: user.created_at,
+        }
+    }
+}
+
+/// A user's own account as returned by the JSON API: everything but the
+/// password hash and internal bookkeeping.
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicUser {
+    pub id: String,
+    pub email: String,
+    pub username: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub profile_public: bool,
+    pub timezone: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<User> for PublicUser {
+    fn from(user: User) -> Self {
         Self {
             id: user.id,
             email: user.email,
             username: user.username,
-            full_name,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            display_name: user.display_name,
             avatar_url: user.avatar_url,
+            bio: user.bio,
+            profile_public: user.profile_public,
+            timezone: user.timezone,
+            created_at: user.created_at,
         }
     }
 }
 
+/// One address in the `emails` table. The primary address is mirrored in
+/// `users.email`.
+#[derive(Debug, Clone, Serialize)]
+pub struct UserEmail {
+    pub address: String,
+    pub is_primary: bool,
+    pub verified: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Summary of one `/api/bench` run, as stored in `bench_runs`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchRun {
+    pub kind: String,
+    pub ops: i64,
+    pub seconds: f64,
+    pub ops_per_sec: f64,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginForm {
     pub email: String,
     pub password: String,
     pub remember_me: Option<bool>,
+    pub next: Option<String>,
+    #[serde(default)]
+    pub csrf_token: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -81,31 +210,46 @@ pub struct RegisterForm {
     pub password_confirm: String,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
+    #[serde(default)]
+    pub csrf_token: String,
 }
 
 impl RegisterForm {
+    /// Returns a copy with surrounding whitespace stripped from the identity
+    /// and name fields; blank names become `None`. Passwords are left as typed.
+    pub fn normalized(&self) -> Self {
+        Self {
+            email: self.email.trim().to_string(),
+            username: self.username.trim().to_string(),
+            password: self.password.clone(),
+            password_confirm: self.password_confirm.clone(),
+            first_name: trim_optional(&self.first_name),
+            last_name: trim_optional(&self.last_name),
+            csrf_token: self.csrf_token.clone(),
+        }
+    }
+
     pub fn validate(&self) -> Result<(), Vec<String>> {
+        let form = self.normalized();
         let mut errors = Vec::new();
 
-        if self.email.is_empty() {
+        if form.email.is_empty() {
             errors.push("Email is required".to_string());
-        } else if !self.email.contains('@') {
+        } else if !form.email.contains('@') {
             errors.push("Please enter a valid email address".to_string());
         }
 
-        if self.username.is_empty() {
-            errors.push("Username is required".to_string());
-        } else if self.username.len() < 3 {
-            errors.push("Username must be at least 3 characters long".to_string());
+        if let Some(error) = username_error(&form.username) {
+            errors.push(error);
         }
 
-        if self.password.is_empty() {
+        if form.password.is_empty() {
             errors.push("Password is required".to_string());
-        } else if self.password.len() < 6 {
+        } else if form.password.len() < 6 {
             errors.push("Password must be at least 6 characters long".to_string());
         }
 
-        if self.password != self.password_confirm {
+        if form.password != form.password_confirm {
             errors.push("Passwords do not match".to_string());
         }
 
@@ -115,4 +259,398 @@ impl RegisterForm {
             Err(errors)
         }
     }
-}
\ No newline at end of file
+}
+
+fn username_error(username: &str) -> Option<String> {
+    if username.is_empty() {
+        Some("Username is required".to_string())
+    } else if username.len() < 3 {
+        Some("Username must be at least 3 characters long".to_string())
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeUsernameForm {
+    pub username: String,
+    #[serde(default)]
+    pub csrf_token: String,
+}
+
+impl ChangeUsernameForm {
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        match username_error(self.username.trim()) {
+            Some(error) => Err(vec![error]),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordForm {
+    pub current_password: String,
+    pub new_password: String,
+    pub new_password_confirm: String,
+    #[serde(default)]
+    pub csrf_token: String,
+}
+
+impl ChangePasswordForm {
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if self.current_password.is_empty() {
+            errors.push("Current password is required".to_string());
+        }
+
+        if self.new_password.len() < 6 {
+            errors.push("New password must be at least 6 characters long".to_string());
+        }
+
+        if self.new_password != self.new_password_confirm {
+            errors.push("New passwords do not match".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// A form whose only field is the CSRF token, for plain action buttons.
+#[derive(Debug, Deserialize)]
+pub struct CsrfForm {
+    #[serde(default)]
+    pub csrf_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyPasswordForm {
+    pub password: String,
+    pub next: Option<String>,
+    #[serde(default)]
+    pub csrf_token: String,
+}
+
+/// Longest first, last or display name accepted, in characters.
+pub const MAX_NAME_CHARS: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileUpdateForm {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub display_name: Option<String>,
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub csrf_token: String,
+}
+
+impl ProfileUpdateForm {
+    /// Returns a copy with names and timezone trimmed; blank values become
+    /// `None`.
+    pub fn normalized(&self) -> Self {
+        Self {
+            first_name: trim_optional(&self.first_name),
+            last_name: trim_optional(&self.last_name),
+            display_name: trim_optional(&self.display_name),
+            timezone: trim_optional(&self.timezone),
+            csrf_token: self.csrf_token.clone(),
+        }
+    }
+
+    /// The chosen timezone, or UTC when left blank.
+    pub fn timezone(&self) -> &str {
+        self.timezone.as_deref().unwrap_or("UTC")
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let form = self.normalized();
+        let mut errors: Vec<String> = [
+            ("First name", &form.first_name),
+            ("Last name", &form.last_name),
+            ("Display name", &form.display_name),
+        ]
+        .into_iter()
+        .filter(|(_, value)| value.as_deref().is_some_and(|v| v.chars().count() > MAX_NAME_CHARS))
+        .map(|(label, _)| format!("{} must be at most {} characters", label, MAX_NAME_CHARS))
+        .collect();
+
+        if !is_valid_timezone(form.timezone()) {
+            errors.push(format!("{} is not a known timezone", form.timezone()));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Longest bio accepted on the public profile, in characters.
+pub const MAX_BIO_CHARS: usize = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct PublicProfileForm {
+    #[serde(default)]
+    pub bio: String,
+    pub profile_public: Option<bool>,
+    #[serde(default)]
+    pub csrf_token: String,
+}
+
+impl PublicProfileForm {
+    /// The trimmed bio, or `None` when left blank.
+    pub fn bio(&self) -> Option<String> {
+        let bio = self.bio.trim();
+        (!bio.is_empty()).then(|| bio.to_string())
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        if self.bio.trim().chars().count() > MAX_BIO_CHARS {
+            return Err(vec![format!("Bio must be at most {} characters", MAX_BIO_CHARS)]);
+        }
+        Ok(())
+    }
+}
+
+/// Longest avatar URL accepted, in characters.
+pub const MAX_AVATAR_URL_CHARS: usize = 2048;
+
+/// Body of `PATCH /api/me`. Each field is `None` when the key is absent and
+/// left untouched, `Some(None)` when it is `null` and cleared, and
+/// `Some(Some(_))` when it is set. Unknown keys are rejected.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfilePatch {
+    #[serde(default, deserialize_with = "present")]
+    pub first_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub last_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub display_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub avatar_url: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub bio: Option<Option<String>>,
+}
+
+impl ProfilePatch {
+    /// Returns a copy with provided values trimmed; blank strings clear the
+    /// field like `null` does.
+    pub fn normalized(&self) -> Self {
+        Self {
+            first_name: self.first_name.as_ref().map(trim_optional),
+            last_name: self.last_name.as_ref().map(trim_optional),
+            display_name: self.display_name.as_ref().map(trim_optional),
+            avatar_url: self.avatar_url.as_ref().map(trim_optional),
+            bio: self.bio.as_ref().map(trim_optional),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let form = self.normalized();
+        let set = |field: &Option<Option<String>>| field.clone().flatten();
+        let mut errors = Vec::new();
+
+        for (label, value) in [
+            ("First name", set(&form.first_name)),
+            ("Last name", set(&form.last_name)),
+            ("Display name", set(&form.display_name)),
+        ] {
+            if value.is_some_and(|v| v.chars().count() > MAX_NAME_CHARS) {
+                errors.push(format!("{} must be at most {} characters", label, MAX_NAME_CHARS));
+            }
+        }
+
+        if let Some(url) = set(&form.avatar_url) {
+            if !(url.starts_with("https://") || url.starts_with("http://")) {
+                errors.push("Avatar URL must start with http:// or https://".to_string());
+            } else if url.chars().count() > MAX_AVATAR_URL_CHARS {
+                errors.push(format!("Avatar URL must be at most {} characters", MAX_AVATAR_URL_CHARS));
+            }
+        }
+
+        if set(&form.bio).is_some_and(|bio| bio.chars().count() > MAX_BIO_CHARS) {
+            errors.push(format!("Bio must be at most {} characters", MAX_BIO_CHARS));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Deserializes a key that is present (even as `null`) to `Some`, so that
+/// with `#[serde(default)]` an absent key stays `None`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Whether `name` is a timezone in the IANA database, e.g. `Asia/Tokyo`.
+pub fn is_valid_timezone(name: &str) -> bool {
+    name.parse::<chrono_tz::Tz>().is_ok()
+}
+
+fn trim_optional(value: &Option<String>) -> Option<String> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ContactForm {
+    pub name: String,
+    pub email: String,
+    pub message: String,
+    // Honeypot: hidden from real visitors, so any value means a bot filled it in.
+    #[serde(default)]
+    pub website: String,
+    #[serde(default, skip_serializing)]
+    pub csrf_token: String,
+}
+
+impl ContactForm {
+    pub fn is_spam(&self) -> bool {
+        !self.website.trim().is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if self.name.trim().is_empty() {
+            errors.push("Name is required".to_string());
+        } else if self.name.len() > 100 {
+            errors.push("Name must be at most 100 characters long".to_string());
+        }
+
+        if self.email.trim().is_empty() {
+            errors.push("Email is required".to_string());
+        } else if !self.email.contains('@') {
+            errors.push("Please enter a valid email address".to_string());
+        }
+
+        if self.message.trim().is_empty() {
+            errors.push("Message is required".to_string());
+        } else if self.message.len() > 5000 {
+            errors.push("Message must be at most 5000 characters long".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(email: &str) -> User {
+        User::new(email.to_string(), "ada".to_string(), "hash".to_string())
+    }
+
+    #[test]
+    fn gravatar_hashes_the_normalized_email() {
+        let user = user("  MyEmailAddress@Example.com ");
+        assert_eq!(
+            user.avatar_or_gravatar(Some("identicon")).as_deref(),
+            Some("https://www.gravatar.com/avatar/0bc83cb571cd1c50ba6f3e8a78ef1346?d=identicon")
+        );
+    }
+
+    #[test]
+    fn stored_avatar_takes_precedence_over_gravatar() {
+        let mut user = user("ada@example.com");
+        assert_eq!(user.avatar_or_gravatar(None), None);
+
+        user.avatar_url = Some("https://cdn.example.com/ada.png".to_string());
+        assert_eq!(
+            user.avatar_or_gravatar(Some("identicon")).as_deref(),
+            Some("https://cdn.example.com/ada.png")
+        );
+        assert_eq!(user.avatar_or_gravatar(None).as_deref(), Some("https://cdn.example.com/ada.png"));
+    }
+
+    #[test]
+    fn display_name_precedence() {
+        let mut user = user("ada@example.com");
+        assert_eq!(user.display_name(), "ada");
+
+        user.last_name = Some("Lovelace".to_string());
+        assert_eq!(user.display_name(), "Lovelace");
+        user.first_name = Some("Ada".to_string());
+        assert_eq!(user.display_name(), "Ada Lovelace");
+
+        user.display_name = Some("Countess".to_string());
+        assert_eq!(user.display_name(), "Countess");
+        assert_eq!(user.full_name(), "Ada Lovelace");
+    }
+
+    #[test]
+    fn blank_names_fall_back_to_the_username() {
+        let mut user = user("ada@example.com");
+        user.display_name = Some(String::new());
+        user.first_name = Some("  ".to_string());
+        user.last_name = Some(String::new());
+        assert_eq!(user.display_name(), "ada");
+    }
+
+    #[test]
+    fn only_iana_timezones_are_valid() {
+        for name in ["UTC", "Europe/Stockholm", "America/Argentina/Buenos_Aires", "Asia/Tokyo"] {
+            assert!(is_valid_timezone(name), "{name}");
+        }
+        for name in ["", "Mars/Olympus_Mons", "GMT+25", "Europe/", "europe/stockholm "] {
+            assert!(!is_valid_timezone(name), "{name}");
+        }
+    }
+
+    fn patch(json: &str) -> ProfilePatch {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn profile_patch_tells_absent_from_null() {
+        let update = patch(r#"{"first_name": "Ada", "bio": null}"#);
+        assert_eq!(update.first_name, Some(Some("Ada".to_string())));
+        assert_eq!(update.bio, Some(None));
+        assert_eq!(update.last_name, None);
+        assert_eq!(update.avatar_url, None);
+
+        let empty = patch("{}");
+        assert!(empty.first_name.is_none() && empty.bio.is_none() && empty.display_name.is_none());
+        assert!(serde_json::from_str::<ProfilePatch>(r#"{"password_hash": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn profile_patch_blank_strings_clear() {
+        let update = patch(r#"{"display_name": "  ", "last_name": " Lovelace "}"#).normalized();
+        assert_eq!(update.display_name, Some(None));
+        assert_eq!(update.last_name, Some(Some("Lovelace".to_string())));
+    }
+
+    #[test]
+    fn profile_patch_validates_provided_fields() {
+        assert!(patch(r#"{"avatar_url": null, "bio": "Hi"}"#).validate().is_ok());
+
+        let long_name = "x".repeat(MAX_NAME_CHARS + 1);
+        let invalid = patch(&format!(r#"{{"first_name": "{}", "avatar_url": "ftp://x"}}"#, long_name));
+        let errors = invalid.validate().unwrap_err();
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors[1].starts_with("Avatar URL must start with"));
+    }
+}
@@ -0,0 +1,159 @@
+use actix_session::Session;
+use actix_web::{dev::Payload, web, Error, FromRequest, HttpRequest};
+use futures_util::future::{ready, Ready};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use crate::auth::{inject_auth, OptionalAuthUser};
+use crate::csrf::with_csrf;
+
+/// A fixed banner shown for a known `msg` code.
+#[derive(Debug, Serialize)]
+pub struct Notice {
+    pub level: &'static str,
+    pub text: &'static str,
+}
+
+// Only these codes produce a banner. The text lives server-side so a crafted
+// link can't put arbitrary wording on our pages.
+const NOTICES: &[(&str, &str, &str)] = &[
+    ("registration_success", "success", "Registration successful! Please log in."),
+    ("logged_out", "success", "You have been successfully logged out."),
+    ("login_required", "warning", "Please log in to access that page."),
+    ("session_expired", "warning", "Your session has expired. Please log in again."),
+    ("username_changed", "success", "Your username has been updated."),
+    ("message_sent", "success", "Thanks! Your message has been sent."),
+    ("profile_updated", "success", "Your public profile has been updated."),
+    ("details_updated", "success", "Your personal information has been updated."),
+    ("session_refreshed", "success", "Your session has been refreshed."),
+    ("password_changed", "success", "Your password has been changed."),
+    ("email_unverified", "warning", "Your email address must be verified before making that change. Please contact support to verify it."),
+];
+
+/// The banner for a `msg` code, or `None` for unknown codes.
+pub fn notice(code: &str) -> Option<Notice> {
+    NOTICES
+        .iter()
+        .find(|(known, _, _)| *known == code)
+        .map(|&(_, level, text)| Notice { level, text })
+}
+
+#[derive(Deserialize)]
+struct MsgQuery {
+    msg: Option<String>,
+}
+
+/// The Tera context every form page starts from: `brand_name`, the login
+/// state (`user`, `is_authenticated`), `csrf_token`/`csrf_field`, and the
+/// `notice` banner when the URL carries a known `?msg=` code. Handlers add
+/// their page-specific keys to `.0` and render it.
+///
+/// Issuing the CSRF token starts a session, so pages without forms should
+/// keep building their context by hand.
+pub struct PageContext(pub Context);
+
+impl FromRequest for PageContext {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(page_context(req).map(PageContext))
+    }
+}
+
+fn page_context(req: &HttpRequest) -> Result<Context, Error> {
+    let mut ctx = Context::new();
+    ctx.insert("brand_name", "Rust Web AI");
+
+    let user = OptionalAuthUser::extract(req).into_inner()?;
+    let session = Session::extract(req).into_inner()?;
+    inject_auth(&mut ctx, &user, &session);
+    with_csrf(&mut ctx, &session)?;
+
+    let code = web::Query::<MsgQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().msg);
+    if let Some(notice) = code.as_deref().and_then(notice) {
+        ctx.insert("notice", &notice);
+    }
+
+    Ok(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use crate::auth::{login_user, RememberMePolicy};
+    use crate::config::Config;
+    use crate::models::SessionUser;
+    use crate::test_support::{client, session_middleware};
+
+    #[test]
+    fn only_known_codes_have_a_notice() {
+        let logged_out = notice("logged_out").unwrap();
+        assert_eq!(logged_out.level, "success");
+        assert_eq!(logged_out.text, "You have been successfully logged out.");
+        assert!(notice("Your account was suspended").is_none());
+        assert!(notice("").is_none());
+    }
+
+    #[actix_web::test]
+    async fn unknown_msg_renders_no_banner() {
+        let mut client = client(Config::for_tests()).await;
+
+        let known = client.get("/login?msg=logged_out").await.body;
+        assert!(known.contains("You have been successfully logged out."));
+
+        let unknown = client.get("/login?msg=Call%20us%20at%20555-0100").await.body;
+        assert!(!unknown.contains("555-0100"));
+        assert!(!unknown.contains(r#"role="alert""#));
+    }
+
+    async fn show(page: PageContext) -> HttpResponse {
+        HttpResponse::Ok().json(page.0.into_json())
+    }
+
+    async fn log_in(req: HttpRequest, session: Session) -> HttpResponse {
+        let user = SessionUser {
+            id: "user-1".to_string(),
+            email: "ada@example.com".to_string(),
+            username: "ada".to_string(),
+            display_name: "Ada".to_string(),
+            avatar_url: None,
+            timezone: "UTC".to_string(),
+        };
+        login_user(&req, &session, user, RememberMePolicy::Short).unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn page_context_has_user_csrf_and_notice() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Config::for_tests()))
+                .wrap(session_middleware())
+                .route("/login", web::post().to(log_in))
+                .route("/page", web::get().to(show)),
+        )
+        .await;
+
+        let anonymous: serde_json::Value =
+            call_and_read_body_json(&app, TestRequest::get().uri("/page?msg=bogus").to_request()).await;
+        assert_eq!(anonymous["brand_name"], "Rust Web AI");
+        assert_eq!(anonymous["is_authenticated"], false);
+        assert!(anonymous.get("user").is_none());
+        assert!(anonymous.get("notice").is_none());
+        assert_eq!(anonymous["csrf_token"].as_str().map(str::len), Some(32));
+
+        let res = call_service(&app, TestRequest::post().uri("/login").to_request()).await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let req = TestRequest::get().uri("/page?msg=logged_out").cookie(cookie).to_request();
+        let page: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(page["is_authenticated"], true);
+        assert_eq!(page["user"]["username"], "ada");
+        let token = page["csrf_token"].as_str().unwrap();
+        assert!(page["csrf_field"].as_str().unwrap().contains(token));
+        assert_eq!(page["notice"]["level"], "success");
+    }
+}
@@ -0,0 +1,133 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use std::cell::Cell;
+use std::time::Duration;
+use crate::config::{AppEnv, Config};
+
+#[derive(Clone, Copy, Default)]
+struct QueryStats {
+    count: u32,
+    total: Duration,
+}
+
+tokio::task_local! {
+    static QUERY_STATS: Cell<QueryStats>;
+}
+
+/// Counts a finished database query against the current request. Does
+/// nothing outside a request, e.g. during startup.
+pub fn record(elapsed: Duration) {
+    let _ = QUERY_STATS.try_with(|stats| {
+        let current = stats.get();
+        stats.set(QueryStats {
+            count: current.count + 1,
+            total: current.total + elapsed,
+        });
+    });
+}
+
+/// Counts the database queries each request makes and warns when a request
+/// goes over `QUERY_COUNT_WARN`, which usually means an N+1 pattern crept
+/// into a handler. In development the count and total query time are also
+/// sent in a `Server-Timing` header, where browser dev tools show them.
+pub async fn track_queries(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let config = req.app_data::<web::Data<Config>>().cloned();
+    let method = req.method().clone();
+    let path = req.path().to_string();
+
+    let (mut response, stats) = QUERY_STATS
+        .scope(Cell::new(QueryStats::default()), async {
+            let response = next.call(req).await;
+            (response, QUERY_STATS.with(Cell::get))
+        })
+        .await;
+
+    let Some(config) = config else {
+        return response;
+    };
+
+    if stats.count as usize > config.query_count_warn {
+        log::warn!(
+            "many queries: {} {} ran {} queries (threshold {})",
+            method,
+            path,
+            stats.count,
+            config.query_count_warn
+        );
+    }
+
+    if config.app_env == AppEnv::Development {
+        if let Ok(response) = response.as_mut() {
+            let timing = format!(
+                "db;desc=\"DB queries: {}\";dur={:.1}",
+                stats.count,
+                stats.total.as_secs_f64() * 1000.0
+            );
+            if let Ok(value) = HeaderValue::from_str(&timing) {
+                response
+                    .headers_mut()
+                    .append(HeaderName::from_static("server-timing"), value);
+            }
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use crate::database::Database;
+    use crate::test_support::{capture_logs, logged};
+
+    async fn three_queries(db: web::Data<Database>) -> HttpResponse {
+        for name in ["ada", "grace", "edsger"] {
+            db.username_exists(name).await.unwrap();
+        }
+        HttpResponse::Ok().finish()
+    }
+
+    async fn no_queries() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    fn server_timing<B>(res: &ServiceResponse<B>) -> &str {
+        res.headers().get("server-timing").unwrap().to_str().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn counts_the_queries_of_each_request() {
+        capture_logs();
+        let config = Config { query_count_warn: 2, ..Config::for_tests() };
+        let db = Database::new(&config).await.unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(db))
+                .wrap(from_fn(track_queries))
+                .route("/query-stats-three", web::get().to(three_queries))
+                .route("/query-stats-none", web::get().to(no_queries)),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/query-stats-three").to_request()).await;
+        assert!(server_timing(&res).starts_with(r#"db;desc="DB queries: 3";dur="#));
+        assert_eq!(
+            logged("/query-stats-three"),
+            ["WARN many queries: GET /query-stats-three ran 3 queries (threshold 2)"]
+        );
+
+        let res = call_service(&app, TestRequest::get().uri("/query-stats-none").to_request()).await;
+        assert!(server_timing(&res).starts_with(r#"db;desc="DB queries: 0";dur="#));
+        assert!(logged("/query-stats-none").is_empty());
+    }
+}
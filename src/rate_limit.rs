@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Most clients tracked at once. Past this, expired windows are dropped
+/// first and then the oldest live one, so a flood of addresses can't grow
+/// the map without bound.
+const MAX_TRACKED: usize = 10_000;
+
+/// Allows at most `max` actions per client IP in each `window`, for forms
+/// anonymous visitors can write through. Shared across workers like
+/// `ConcurrencyLimit`.
+#[derive(Clone)]
+pub struct RateLimit {
+    windows: Arc<Mutex<HashMap<Option<IpAddr>, Window>>>,
+    max: u32,
+    window: Duration,
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+impl RateLimit {
+    /// A `max` of zero turns the limit off.
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            max,
+            window,
+        }
+    }
+
+    /// Counts an action from `ip`. When the client has used up its window
+    /// nothing is counted and the error is the time until the window ends,
+    /// for a `Retry-After` header.
+    pub fn allow(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        if self.max == 0 {
+            return Ok(());
+        }
+
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+
+        if windows.len() >= MAX_TRACKED && !windows.contains_key(&ip) {
+            windows.retain(|_, window| now.duration_since(window.start) < self.window);
+            if windows.len() >= MAX_TRACKED {
+                let oldest = windows.iter().min_by_key(|(_, window)| window.start).map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    windows.remove(&oldest);
+                }
+            }
+        }
+
+        let window = windows.entry(ip).or_insert(Window { start: now, count: 0 });
+        if now.duration_since(window.start) >= self.window {
+            *window = Window { start: now, count: 0 };
+        }
+        if window.count >= self.max {
+            return Err(self.window.saturating_sub(now.duration_since(window.start)));
+        }
+
+        window.count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([10, 0, 0, last]))
+    }
+
+    #[test]
+    fn blocks_past_the_limit_per_ip() {
+        let limit = RateLimit::new(2, Duration::from_secs(60));
+
+        assert!(limit.allow(ip(1)).is_ok());
+        assert!(limit.allow(ip(1)).is_ok());
+        assert!(limit.allow(ip(1)).is_err());
+        assert!(limit.allow(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn blocked_clients_learn_when_the_window_ends() {
+        let limit = RateLimit::new(1, Duration::from_secs(60));
+
+        assert!(limit.allow(ip(1)).is_ok());
+        let retry_after = limit.allow(ip(1)).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(60));
+        assert!(retry_after > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn a_new_window_starts_fresh() {
+        let limit = RateLimit::new(1, Duration::from_millis(20));
+
+        assert!(limit.allow(ip(1)).is_ok());
+        assert!(limit.allow(ip(1)).is_err());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(limit.allow(ip(1)).is_ok());
+    }
+
+    #[test]
+    fn zero_turns_the_limit_off() {
+        let limit = RateLimit::new(0, Duration::from_secs(60));
+
+        assert!((0..100).all(|_| limit.allow(ip(1)).is_ok()));
+    }
+
+    #[test]
+    fn tracked_clients_are_capped() {
+        let limit = RateLimit::new(1, Duration::from_secs(60));
+
+        for i in 0..MAX_TRACKED as u32 + 50 {
+            assert!(limit.allow(Some(IpAddr::from(i.to_be_bytes()))).is_ok());
+        }
+        assert_eq!(limit.windows.lock().unwrap().len(), MAX_TRACKED);
+    }
+}
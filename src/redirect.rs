@@ -0,0 +1,139 @@
+use actix_web::web;
+
+/// Longest `next` path accepted, in bytes.
+const MAX_NEXT_LEN: usize = 512;
+
+/// Query parameters that usually carry a further redirect target. A `next`
+/// path with one of these is refused so redirects can't be chained.
+const REDIRECT_PARAMS: &[&str] = &[
+    "next", "redirect", "redirect_to", "redirect_uri", "return", "return_to", "continue", "url",
+];
+
+/// Whether `path` points back into this site. Only absolute paths are
+/// accepted; protocol-relative (`//host`) and backslash forms are rejected
+/// because browsers treat them as links to another origin.
+pub fn is_local_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && !path.chars().any(char::is_control)
+}
+
+/// A `next` target that is safe to send as a `Location`: a local path of at
+/// most `MAX_NEXT_LEN` bytes whose query carries no further redirect (a
+/// `next`-like parameter or an encoded absolute URL), with `.`/`..`
+/// segments and repeated slashes resolved. `None` means use the default.
+pub fn safe_next(next: &str) -> Option<String> {
+    if next.len() > MAX_NEXT_LEN || !is_local_path(next) {
+        return None;
+    }
+
+    let (rest, fragment) = match next.split_once('#') {
+        Some((rest, fragment)) => (rest, Some(fragment)),
+        None => (next, None),
+    };
+    let (path, query) = match rest.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (rest, None),
+    };
+
+    if let Some(query) = query {
+        let params = web::Query::<Vec<(String, String)>>::from_query(query).ok()?;
+        let chained = params.iter().any(|(key, value)| {
+            REDIRECT_PARAMS.contains(&key.to_ascii_lowercase().as_str())
+                || value.contains("://")
+                || value.starts_with("//")
+        });
+        if chained {
+            return None;
+        }
+    }
+
+    let mut target = normalize_path(path);
+    if let Some(query) = query {
+        target.push('?');
+        target.push_str(query);
+    }
+    if let Some(fragment) = fragment {
+        target.push('#');
+        target.push_str(fragment);
+    }
+    Some(target)
+}
+
+/// Resolves `.` and `..` segments and collapses repeated slashes, keeping a
+/// trailing slash. `..` never climbs above `/`.
+fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if path.ends_with('/') && !segments.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Where to send a user after logging in: the requested `next` path when
+/// `safe_next` accepts it, otherwise the configured default.
+pub fn after_login(next: Option<&str>, default: &str) -> String {
+    next.and_then(safe_next).unwrap_or_else(|| default.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simple_paths_are_kept() {
+        assert_eq!(safe_next("/dashboard").as_deref(), Some("/dashboard"));
+        assert_eq!(safe_next("/posts?page=2#top").as_deref(), Some("/posts?page=2#top"));
+        assert_eq!(safe_next("/").as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn other_origins_are_refused() {
+        for next in ["//evil.example", "/\\evil.example", "https://evil.example", "evil.example", "/\tevil", ""] {
+            assert!(!is_local_path(next), "{:?}", next);
+            assert_eq!(safe_next(next), None, "{:?}", next);
+        }
+    }
+
+    #[test]
+    fn chained_redirects_are_refused() {
+        assert_eq!(safe_next("/login?next=/admin"), None);
+        assert_eq!(safe_next("/login?page=1&Return_To=/admin"), None);
+        assert_eq!(safe_next("/go?to=https%3A%2F%2Fevil.example"), None);
+        assert_eq!(safe_next("/go?to=%2F%2Fevil.example"), None);
+    }
+
+    #[test]
+    fn overlong_paths_are_refused() {
+        let at_limit = format!("/{}", "a".repeat(MAX_NEXT_LEN - 1));
+        assert_eq!(safe_next(&at_limit), Some(at_limit.clone()));
+        assert_eq!(safe_next(&format!("{}a", at_limit)), None);
+    }
+
+    #[test]
+    fn dot_segments_and_repeated_slashes_are_resolved() {
+        assert_eq!(safe_next("/a/./b/../c").as_deref(), Some("/a/c"));
+        assert_eq!(safe_next("/../../etc").as_deref(), Some("/etc"));
+        assert_eq!(safe_next("/..//evil.example").as_deref(), Some("/evil.example"));
+        assert_eq!(safe_next("/posts//drafts/").as_deref(), Some("/posts/drafts/"));
+    }
+
+    #[test]
+    fn after_login_falls_back_to_the_default() {
+        assert_eq!(after_login(Some("/settings"), "/dashboard"), "/settings");
+        assert_eq!(after_login(Some("//evil.example"), "/dashboard"), "/dashboard");
+        assert_eq!(after_login(None, "/dashboard"), "/dashboard");
+    }
+}
@@ -0,0 +1,156 @@
+use thiserror::Error;
+use crate::database::Database;
+use crate::models::{RegisterForm, User};
+
+#[derive(Debug, Error)]
+pub enum RegisterError {
+    #[error("{}", .0.join(", "))]
+    Invalid(Vec<String>),
+    #[error("Email address is already registered")]
+    EmailTaken,
+    #[error("Username is already taken")]
+    UsernameTaken,
+    #[error("Database error: {0}")]
+    Database(#[from] anyhow::Error),
+}
+
+impl RegisterError {
+    /// The messages to show next to the registration form.
+    pub fn messages(&self) -> Vec<String> {
+        match self {
+            RegisterError::Invalid(errors) => errors.clone(),
+            other => vec![other.to_string()],
+        }
+    }
+}
+
+/// User-facing account operations, kept free of HTTP concerns so handlers
+/// only translate between requests and these results.
+#[derive(Clone)]
+pub struct UserService {
+    db: Database,
+}
+
+impl UserService {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    /// Validates, checks for duplicate email/username and creates the account.
+    /// The account and its primary address are inserted in one transaction,
+    /// so a duplicate that slips past the checks (a concurrent registration)
+    /// rolls back and is reported like any other.
+    pub async fn register(&self, form: RegisterForm) -> Result<User, RegisterError> {
+        let form = form.normalized();
+        form.validate().map_err(RegisterError::Invalid)?;
+
+        if self.db.email_exists(&form.email).await? {
+            return Err(RegisterError::EmailTaken);
+        }
+        if self.db.username_exists(&form.username).await? {
+            return Err(RegisterError::UsernameTaken);
+        }
+
+        self.db
+            .create_user(form)
+            .await
+            .map_err(|e| taken_error(&e).unwrap_or(RegisterError::Database(e)))
+    }
+}
+
+/// The duplicate a failed insert ran into, if it hit a unique constraint.
+fn taken_error(e: &anyhow::Error) -> Option<RegisterError> {
+    let db_error = e.downcast_ref::<sqlx::Error>()?.as_database_error()?;
+    if !db_error.is_unique_violation() {
+        return None;
+    }
+
+    if db_error.message().contains("users.username") {
+        Some(RegisterError::UsernameTaken)
+    } else {
+        Some(RegisterError::EmailTaken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support::register_form;
+
+    async fn service() -> UserService {
+        UserService::new(Database::new(&Config::for_tests()).await.expect("in-memory database"))
+    }
+
+    #[tokio::test]
+    async fn register_creates_the_account() {
+        let service = service().await;
+
+        let user = service.register(register_form("ada@example.com", "ada")).await.unwrap();
+        assert_eq!(user.email, "ada@example.com");
+        let emails = service.db.list_emails(&user.id).await.unwrap();
+        assert_eq!(emails.len(), 1);
+        assert!(emails[0].is_primary);
+    }
+
+    #[tokio::test]
+    async fn register_rejects_invalid_forms() {
+        let service = service().await;
+
+        let mut invalid = register_form("not-an-email", "ada");
+        invalid.password_confirm = "different".to_string();
+        match service.register(invalid).await {
+            Err(RegisterError::Invalid(errors)) => assert_eq!(errors.len(), 2, "{errors:?}"),
+            other => panic!("expected Invalid, got {other:?}"),
+        }
+        assert!(!service.db.username_exists("ada").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn register_rejects_duplicates() {
+        let service = service().await;
+        let user = service.register(register_form("ada@example.com", "ada")).await.unwrap();
+        service.db.add_email(&user.id, "countess@example.com").await.unwrap();
+
+        let result = service.register(register_form("ada@example.com", "other")).await;
+        assert!(matches!(result, Err(RegisterError::EmailTaken)), "{result:?}");
+        let result = service.register(register_form("countess@example.com", "other")).await;
+        assert!(matches!(result, Err(RegisterError::EmailTaken)), "{result:?}");
+        let result = service.register(register_form("new@example.com", "ada")).await;
+        assert!(matches!(result, Err(RegisterError::UsernameTaken)), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn constraint_failures_map_to_taken_errors() {
+        let service = service().await;
+        service.register(register_form("ada@example.com", "ada")).await.unwrap();
+
+        // Straight to the insert, as a registration that passed the checks
+        // before a concurrent one committed would
+        let e = service.db.create_user(register_form("ada@example.com", "other")).await.unwrap_err();
+        assert!(matches!(taken_error(&e), Some(RegisterError::EmailTaken)));
+        let e = service.db.create_user(register_form("new@example.com", "ada")).await.unwrap_err();
+        assert!(matches!(taken_error(&e), Some(RegisterError::UsernameTaken)));
+        assert!(!service.db.email_exists("new@example.com").await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn racing_registrations_report_the_duplicate() {
+        let service = service().await;
+
+        let racers = (0..4).map(|i| {
+            let service = service.clone();
+            tokio::spawn(async move { service.register(register_form("ada@example.com", &format!("ada{}", i))).await })
+        });
+        let results: Vec<_> = futures_util::future::join_all(racers)
+            .await
+            .into_iter()
+            .map(|joined| joined.unwrap())
+            .collect();
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        for result in results.iter().filter(|result| result.is_err()) {
+            assert!(matches!(result, Err(RegisterError::EmailTaken)), "{result:?}");
+        }
+    }
+}
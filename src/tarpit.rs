@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Failures older than this are forgotten.
+const FORGET_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Most email/IP pairs tracked at once. Past this the least recently failed
+/// pair is dropped, so guessing across many emails can't grow the map
+/// without bound.
+const MAX_TRACKED: usize = 10_000;
+
+/// Failures are counted per normalized email and client IP.
+type FailureKey = (String, Option<IpAddr>);
+
+/// Slows down repeated failed logins instead of locking accounts out. Each
+/// failure for the same email and client IP doubles the delay before the
+/// next failed attempt is answered, from `base` up to `max`. A successful
+/// login clears the count. Shared across workers like `ConcurrencyLimit`.
+#[derive(Clone)]
+pub struct LoginTarpit {
+    failures: Arc<Mutex<HashMap<FailureKey, Failures>>>,
+    base: Duration,
+    max: Duration,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl LoginTarpit {
+    /// A `base` of zero turns the tarpit off.
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            failures: Arc::new(Mutex::new(HashMap::new())),
+            base,
+            max,
+        }
+    }
+
+    /// Records a failed login and waits out the resulting delay. The wait is
+    /// an async sleep, so the worker keeps serving other requests.
+    pub async fn fail(&self, email: &str, ip: Option<IpAddr>) {
+        if self.base.is_zero() {
+            return;
+        }
+
+        let count = self.record_failure(email, ip);
+        tokio::time::sleep(delay(self.base, self.max, count)).await;
+    }
+
+    /// Counts a failure and returns how many there have been in a row.
+    fn record_failure(&self, email: &str, ip: Option<IpAddr>) -> u32 {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        failures.retain(|_, entry| now.duration_since(entry.last) < FORGET_AFTER);
+
+        let key = key(email, ip);
+        if failures.len() >= MAX_TRACKED && !failures.contains_key(&key) {
+            let oldest = failures.iter().min_by_key(|(_, entry)| entry.last).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                failures.remove(&oldest);
+            }
+        }
+
+        let entry = failures.entry(key).or_insert(Failures { count: 0, last: now });
+        entry.count = entry.count.saturating_add(1);
+        entry.last = now;
+        entry.count
+    }
+
+    /// Clears the failure count after a successful login.
+    pub fn succeed(&self, email: &str, ip: Option<IpAddr>) {
+        if self.base.is_zero() {
+            return;
+        }
+
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.remove(&key(email, ip));
+    }
+}
+
+fn key(email: &str, ip: Option<IpAddr>) -> FailureKey {
+    (email.trim().to_lowercase(), ip)
+}
+
+/// `base` after the first failure, doubling with each further one, capped
+/// at `max`.
+fn delay(base: Duration, max: Duration, failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    base.saturating_mul(1 << doublings).min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tarpit() -> LoginTarpit {
+        LoginTarpit::new(Duration::from_millis(100), Duration::from_secs(1))
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_max() {
+        let (base, max) = (Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<u128> = (1..=6).map(|failures| delay(base, max, failures).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(delay(base, max, 0), base);
+        assert_eq!(delay(base, Duration::MAX, u32::MAX), base * (1 << 16));
+    }
+
+    #[test]
+    fn failures_count_per_email_and_ip_until_success() {
+        let tarpit = tarpit();
+        let ip = Some(IpAddr::from([10, 0, 0, 1]));
+
+        assert_eq!(tarpit.record_failure("ada@example.com", ip), 1);
+        assert_eq!(tarpit.record_failure(" ADA@example.com", ip), 2);
+        assert_eq!(tarpit.record_failure("ada@example.com", None), 1);
+        assert_eq!(tarpit.record_failure("grace@example.com", ip), 1);
+
+        tarpit.succeed("ada@example.com", ip);
+        assert_eq!(tarpit.record_failure("ada@example.com", ip), 1);
+    }
+
+    #[test]
+    fn tracked_failures_are_capped() {
+        let tarpit = tarpit();
+        for i in 0..MAX_TRACKED + 5 {
+            tarpit.record_failure(&format!("user{}@example.com", i), None);
+        }
+
+        let failures = tarpit.failures.lock().unwrap();
+        assert_eq!(failures.len(), MAX_TRACKED);
+        assert!(failures.contains_key(&key(&format!("user{}@example.com", MAX_TRACKED + 4), None)));
+    }
+}
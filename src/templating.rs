@@ -0,0 +1,385 @@
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tera::{Filter, Function, Tera, Value};
+use crate::assets::{AssetFn, AssetManifest};
+
+/// Registers the app's custom filters and functions on a Tera instance.
+pub fn register(tera: &mut Tera, assets: web::Data<AssetManifest>) {
+    tera.register_filter("json_attr", JsonAttr);
+    tera.register_filter("localtime", LocalTime);
+    tera.register_function("asset", AssetFn(assets));
+    tera.register_function("page_url", PageUrl);
+}
+
+static MINIFY_HTML: OnceLock<bool> = OnceLock::new();
+
+/// Turns HTML minification in `html_response` on or off. Called once at
+/// startup from `MINIFY_HTML`; later calls are ignored.
+pub fn set_minify_html(enabled: bool) {
+    let _ = MINIFY_HTML.set(enabled);
+}
+
+/// A 200 response for a rendered page, minified when `MINIFY_HTML` is on.
+pub fn html_response(body: String) -> HttpResponse {
+    let body = if MINIFY_HTML.get().copied().unwrap_or(false) {
+        minify(&body)
+    } else {
+        body
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+/// Collapses runs of whitespace between and around tags to a single space
+/// and strips comments, which is all browsers would do with them anyway.
+/// Tags and their attributes are copied as written, and the contents of
+/// `<pre>`, `<textarea>`, `<script>` and `<style>` are never touched.
+fn minify(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html.trim_matches(is_html_space);
+
+    while !rest.is_empty() {
+        if rest.starts_with("<!--") {
+            match rest.find("-->") {
+                Some(end) => rest = &rest[end + 3..],
+                None => {
+                    out.push_str(rest);
+                    break;
+                }
+            }
+        } else if rest.starts_with('<') {
+            let tag = &rest[..tag_len(rest)];
+            out.push_str(tag);
+            rest = &rest[tag.len()..];
+
+            if let Some(name) = raw_text_element(tag) {
+                let end = find_closing_tag(rest, name).unwrap_or(rest.len());
+                out.push_str(&rest[..end]);
+                rest = &rest[end..];
+            }
+        } else if rest.starts_with(is_html_space) {
+            out.push(' ');
+            rest = rest.trim_start_matches(is_html_space);
+        } else {
+            let end = rest
+                .find(|c: char| c == '<' || is_html_space(c))
+                .unwrap_or(rest.len());
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+        }
+    }
+
+    out
+}
+
+/// HTML only collapses ASCII whitespace; `&nbsp;` and friends are content.
+fn is_html_space(c: char) -> bool {
+    c.is_ascii_whitespace()
+}
+
+/// Length of the tag at the start of `html`, up to and including its `>`,
+/// skipping any `>` inside quoted attribute values. A quote with no match
+/// after it is an ordinary character, so a stray one ends the tag at the
+/// next `>` instead of swallowing the rest of the page.
+fn tag_len(html: &str) -> usize {
+    // Quotes known to have no match further on
+    let mut unmatched = Vec::new();
+    let mut i = 1;
+    while let Some(offset) = html[i..].find(['>', '"', '\'']) {
+        let at = i + offset;
+        let c = char::from(html.as_bytes()[at]);
+        if c == '>' {
+            return at + 1;
+        }
+
+        i = at + 1;
+        if !unmatched.contains(&c) {
+            match html[i..].find(c) {
+                Some(close) => i += close + 1,
+                None => unmatched.push(c),
+            }
+        }
+    }
+    html.len()
+}
+
+/// Offset of the `</name` that closes a raw text element, matching the name
+/// case-insensitively without copying the document.
+fn find_closing_tag(html: &str, name: &str) -> Option<usize> {
+    html.match_indices("</").map(|(at, _)| at).find(|&at| {
+        html.as_bytes()[at + 2..]
+            .get(..name.len())
+            .is_some_and(|candidate| candidate.eq_ignore_ascii_case(name.as_bytes()))
+    })
+}
+
+/// The element name if `tag` opens an element whose contents must be kept
+/// byte for byte.
+fn raw_text_element(tag: &str) -> Option<&'static str> {
+    let name: String = tag[1..]
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    ["pre", "textarea", "script", "style"]
+        .into_iter()
+        .find(|raw| *raw == name)
+}
+
+/// `{{ value | json_attr }}` serializes a value to JSON that is safe to embed
+/// inside a `<script>` block or a single-quoted HTML attribute.
+///
+/// Characters that could end the surrounding context (`<`, `>`, `&`, `'`) and
+/// the JavaScript line separators are written as `\uXXXX` escapes, which JSON
+/// parsers decode back to the original text. Because the output is already
+/// safe, the filter opts out of autoescaping.
+struct JsonAttr;
+
+impl Filter for JsonAttr {
+    fn filter(&self, value: &Value, _args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let json = serde_json::to_string(value)
+            .map_err(|e| tera::Error::msg(format!("json_attr: {}", e)))?;
+        Ok(Value::String(escape_json_for_html(&json)))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+fn escape_json_for_html(json: &str) -> String {
+    let mut out = String::with_capacity(json.len());
+    for c in json.chars() {
+        match c {
+            '<' => out.push_str("\\u003c"),
+            '>' => out.push_str("\\u003e"),
+            '&' => out.push_str("\\u0026"),
+            '\'' => out.push_str("\\u0027"),
+            '\u{2028}' => out.push_str("\\u2028"),
+            '\u{2029}' => out.push_str("\\u2029"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// `{{ timestamp | localtime(tz=user.timezone) }}` shows a UTC timestamp in
+/// an IANA timezone, by default as `2024-03-31 14:05 CEST`; pass `format`
+/// (chrono syntax) to change that. Unknown or missing zones fall back to
+/// UTC so a bad value never breaks a page.
+struct LocalTime;
+
+impl Filter for LocalTime {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let timestamp = value
+            .as_str()
+            .and_then(|text| DateTime::parse_from_rfc3339(text).ok())
+            .ok_or_else(|| tera::Error::msg("localtime: expected an RFC 3339 timestamp"))?
+            .with_timezone(&Utc);
+        let tz = args
+            .get("tz")
+            .and_then(Value::as_str)
+            .and_then(|name| name.parse::<Tz>().ok())
+            .unwrap_or(Tz::UTC);
+        let format = args.get("format").and_then(Value::as_str).unwrap_or("%Y-%m-%d %H:%M %Z");
+
+        Ok(Value::String(timestamp.with_timezone(&tz).format(format).to_string()))
+    }
+}
+
+/// `{{ page_url(query=current_query, page=2) }}` returns a relative `?...` URL
+/// for another page of a list, keeping the other query parameters (filters,
+/// search terms) and replacing `page`. Handlers that paginate insert the
+/// request's raw query string as `current_query`.
+///
+/// The result is autoescaped like any other value, which is what an `href`
+/// attribute needs since the query comes from the request.
+struct PageUrl;
+
+impl Function for PageUrl {
+    fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let page = args
+            .get("page")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| tera::Error::msg("page_url: missing integer argument `page`"))?;
+        let query = args.get("query").and_then(Value::as_str).unwrap_or_default();
+
+        Ok(Value::String(with_page(query, page)))
+    }
+}
+
+fn with_page(query: &str, page: u64) -> String {
+    let mut pairs: Vec<String> = query
+        .trim_start_matches('?')
+        .split('&')
+        .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some("page"))
+        .map(str::to_string)
+        .collect();
+    pairs.push(format!("page={}", page));
+
+    format!("?{}", pairs.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+<!DOCTYPE html>
+<html>
+  <head>
+    <!-- page styles -->
+    <style>
+      body  { margin: 0; }
+    </style>
+  </head>
+  <body>
+    <p class="lead   intro" title="a > b">
+      Hello,
+      <b>world</b>&nbsp; !
+    </p>
+    <pre>  keep
+    this  </pre>
+    <textarea name="bio">  two  spaces </textarea>
+    <script>if (a  <  b) { go(); }</script>
+  </body>
+</html>
+"#;
+
+    #[test]
+    fn minify_collapses_whitespace_and_strips_comments() {
+        let minified = minify(SAMPLE);
+
+        assert!(minified.len() < SAMPLE.len());
+        assert!(!minified.contains("page styles"));
+        assert!(minified.starts_with("<!DOCTYPE html> <html>"));
+        assert!(minified.contains("<p class=\"lead   intro\" title=\"a > b\"> Hello, <b>world</b>&nbsp; ! </p>"));
+    }
+
+    #[test]
+    fn minify_keeps_raw_text_elements_verbatim() {
+        let minified = minify(SAMPLE);
+
+        assert!(minified.contains("<pre>  keep\n    this  </pre>"));
+        assert!(minified.contains("<textarea name=\"bio\">  two  spaces </textarea>"));
+        assert!(minified.contains("<script>if (a  <  b) { go(); }</script>"));
+        assert!(minified.contains("body  { margin: 0; }"));
+    }
+
+    #[test]
+    fn minify_finds_closing_tags_in_any_case() {
+        let minified = minify("<SCRIPT>if (a  <  b) {}</Script>  <p>  x  </p>");
+        assert_eq!(minified, "<SCRIPT>if (a  <  b) {}</Script> <p> x </p>");
+        assert_eq!(find_closing_tag("a </b> </STYLE>", "style"), Some(7));
+        assert_eq!(find_closing_tag("a </sty", "style"), None);
+    }
+
+    #[test]
+    fn minify_ends_a_tag_with_a_stray_quote_at_the_next_bracket() {
+        let minified = minify("<p title=\"oops>  one   two  </p>  <b class='x>y'>  z  </b>");
+        assert_eq!(minified, "<p title=\"oops> one two </p> <b class='x>y'> z </b>");
+        assert_eq!(tag_len("<a href='x>"), 11);
+        assert_eq!(tag_len("<a \" b=\"c\">  x"), 11);
+    }
+
+    #[test]
+    fn minify_preserves_visible_text() {
+        // Browsers collapse whitespace runs outside raw elements, so the
+        // text a user sees is unchanged
+        let visible = |html: &str| html.split_ascii_whitespace().collect::<Vec<_>>().join(" ");
+        let without_comment = SAMPLE.replace("<!-- page styles -->", "");
+
+        assert_eq!(visible(&minify(SAMPLE)), visible(&without_comment));
+    }
+
+    #[test]
+    fn escape_json_for_html_escapes_context_breakers() {
+        let json = serde_json::to_string(r#"</script><a href='x'>&"#).unwrap();
+        let escaped = escape_json_for_html(&json);
+        assert_eq!(
+            escaped,
+            r#""\u003c/script\u003e\u003ca href=\u0027x\u0027\u003e\u0026""#
+        );
+        assert_eq!(escape_json_for_html("\u{2028}\u{2029}"), "\\u2028\\u2029");
+    }
+
+    #[test]
+    fn json_attr_round_trips_hostile_labels() {
+        let label = r#"Say "hi" </script><script>alert('x')</script>"#;
+        let mut tera = Tera::default();
+        tera.register_filter("json_attr", JsonAttr);
+        tera.add_raw_template("nav", "<script>const nav = {{ links | json_attr }};</script>")
+            .unwrap();
+        let mut ctx = tera::Context::new();
+        ctx.insert("links", &serde_json::json!([{ "label": label, "href": "/" }]));
+
+        let html = tera.render("nav", &ctx).unwrap();
+        assert_eq!(html.matches("</script>").count(), 1, "{html}");
+        assert!(!html.contains('\''));
+
+        let json = html
+            .strip_prefix("<script>const nav = ")
+            .and_then(|rest| rest.strip_suffix(";</script>"))
+            .unwrap();
+        let links: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(links[0]["label"], label);
+    }
+
+    #[test]
+    fn with_page_keeps_filters_and_replaces_page() {
+        assert_eq!(with_page("q=rust&role=admin&page=3", 4), "?q=rust&role=admin&page=4");
+        assert_eq!(with_page("?page=1&q=a%20b", 2), "?q=a%20b&page=2");
+        assert_eq!(with_page("", 2), "?page=2");
+        // Only the exact `page` parameter is replaced
+        assert_eq!(with_page("pages=9&page=1&page=5", 2), "?pages=9&page=2");
+    }
+
+    #[test]
+    fn page_url_renders_from_the_current_query() {
+        let mut tera = Tera::default();
+        tera.register_function("page_url", PageUrl);
+        tera.add_raw_template("pager.html", r#"<a href="{{ page_url(query=current_query, page=2) }}">"#)
+            .unwrap();
+        let mut ctx = tera::Context::new();
+        ctx.insert("current_query", "q=rust&page=1");
+
+        let html = tera.render("pager.html", &ctx).unwrap();
+        assert_eq!(html, r#"<a href="?q=rust&amp;page=2">"#);
+        assert!(tera.render_str("{{ page_url(query='') }}", &ctx).is_err());
+    }
+
+    fn localtime(timestamp: &str, tz: &str) -> String {
+        let args = HashMap::from([("tz".to_string(), Value::from(tz))]);
+        let local = LocalTime.filter(&Value::from(timestamp), &args).unwrap();
+        local.as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn localtime_follows_dst_in_named_zones() {
+        // Berlin springs forward at 01:00 UTC on 2024-03-31
+        assert_eq!(localtime("2024-03-31T00:30:00Z", "Europe/Berlin"), "2024-03-31 01:30 CET");
+        assert_eq!(localtime("2024-03-31T01:30:00Z", "Europe/Berlin"), "2024-03-31 03:30 CEST");
+        // New York falls back at 06:00 UTC on 2024-11-03, repeating 01:30
+        assert_eq!(localtime("2024-11-03T05:30:00Z", "America/New_York"), "2024-11-03 01:30 EDT");
+        assert_eq!(localtime("2024-11-03T06:30:00Z", "America/New_York"), "2024-11-03 01:30 EST");
+        assert_eq!(localtime("2024-06-01T12:00:00+02:00", "Asia/Tokyo"), "2024-06-01 19:00 JST");
+    }
+
+    #[test]
+    fn localtime_falls_back_to_utc_and_takes_a_format() {
+        assert_eq!(localtime("2024-06-01T12:00:00Z", "Mars/Olympus_Mons"), "2024-06-01 12:00 UTC");
+
+        let args = HashMap::from([
+            ("tz".to_string(), Value::from("Europe/Stockholm")),
+            ("format".to_string(), Value::from("%H:%M")),
+        ]);
+        let local = LocalTime.filter(&Value::from("2024-01-15T08:00:00Z"), &args).unwrap();
+        assert_eq!(local, "09:00");
+        assert!(LocalTime.filter(&Value::from("yesterday"), &HashMap::new()).is_err());
+    }
+}
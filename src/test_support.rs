@@ -0,0 +1,221 @@
+//! Test harness: the real app, built by `build_app`, on an in-memory
+//! database, driven by a client that carries the session cookie between
+//! requests like a browser would.
+
+// Each test module uses only some of the helpers
+#![allow(dead_code)]
+
+use actix_http::Request;
+use actix_session::{storage::CookieSessionStore, SessionMiddleware};
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, Key};
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::{test, web, Error};
+use crate::assets::AssetManifest;
+use crate::auth::SESSION_COOKIE_NAME;
+use crate::config::Config;
+use crate::database::Database;
+use crate::models::RegisterForm;
+use crate::{build_app, tera_engine, AppState};
+use std::sync::{Mutex, Once};
+
+pub const PASSWORD: &str = "secret123";
+
+/// A valid registration with the shared test password.
+pub fn register_form(email: &str, username: &str) -> RegisterForm {
+    RegisterForm {
+        email: email.to_string(),
+        username: username.to_string(),
+        password: PASSWORD.to_string(),
+        password_confirm: PASSWORD.to_string(),
+        first_name: None,
+        last_name: None,
+        csrf_token: String::new(),
+    }
+}
+
+/// A bare cookie session, for tests that mount a few handlers of their own
+/// instead of the whole app.
+pub fn session_middleware() -> SessionMiddleware<CookieSessionStore> {
+    SessionMiddleware::builder(CookieSessionStore::default(), Key::from(&[7; 64]))
+        .cookie_name(SESSION_COOKIE_NAME.to_string())
+        .cookie_secure(false)
+        .build()
+}
+
+pub struct TestClient<S> {
+    app: S,
+    cookie: Option<Cookie<'static>>,
+    pub db: Database,
+    pub state: AppState,
+}
+
+/// A finished response, read in full.
+pub struct Reply {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl Reply {
+    pub fn location(&self) -> &str {
+        self.headers
+            .get("location")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    }
+
+    /// The CSRF token of the first form on the page.
+    pub fn csrf_token(&self) -> String {
+        let marker = r#"name="csrf_token" value=""#;
+        let start = self.body.find(marker).expect("page has a CSRF field") + marker.len();
+        self.body[start..start + 32].to_string()
+    }
+}
+
+/// The app state `main` would build for `config`, on a fresh database.
+pub async fn state(config: Config) -> AppState {
+    let database = Database::new(&config).await.expect("in-memory database");
+    let assets = web::Data::new(AssetManifest::build(config.static_dir()));
+    let tera = tera_engine(assets.clone());
+    AppState::new(config, database, tera, assets)
+}
+
+pub async fn client(
+    config: Config,
+) -> TestClient<impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>> {
+    let state = state(config).await;
+    let app = test::init_service(build_app(state.clone())).await;
+    TestClient {
+        app,
+        cookie: None,
+        db: state.database.clone(),
+        state,
+    }
+}
+
+impl<S, B> TestClient<S>
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+{
+    pub async fn send(&mut self, mut req: test::TestRequest) -> Reply {
+        if let Some(cookie) = &self.cookie {
+            req = req.cookie(cookie.clone());
+        }
+        let res = test::call_service(&self.app, req.to_request()).await;
+
+        let issued = res
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == SESSION_COOKIE_NAME)
+            .map(Cookie::into_owned);
+        if let Some(cookie) = issued {
+            let removed = cookie.max_age().is_some_and(|age| age.is_zero() || age.is_negative());
+            self.cookie = (!removed).then_some(cookie);
+        }
+
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = test::read_body(res).await;
+        Reply {
+            status,
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }
+    }
+
+    pub async fn get(&mut self, uri: &str) -> Reply {
+        self.send(test::TestRequest::get().uri(uri)).await
+    }
+
+    pub async fn post_form(&mut self, uri: &str, form: &[(&str, &str)]) -> Reply {
+        self.send(test::TestRequest::post().uri(uri).set_form(form)).await
+    }
+
+    /// Posts `form` to `uri` with the CSRF token from the form page `page`.
+    pub async fn submit(&mut self, page: &str, uri: &str, form: &[(&str, &str)]) -> Reply {
+        let token = self.get(page).await.csrf_token();
+        let mut form = form.to_vec();
+        form.push(("csrf_token", &token));
+        self.post_form(uri, &form).await
+    }
+
+    pub fn session_cookie(&self) -> Option<&Cookie<'static>> {
+        self.cookie.as_ref()
+    }
+
+    pub fn clear_cookies(&mut self) {
+        self.cookie = None;
+    }
+
+    pub async fn register(&mut self, email: &str, username: &str) -> Reply {
+        self.submit(
+            "/register",
+            "/register",
+            &[
+                ("email", email),
+                ("username", username),
+                ("password", PASSWORD),
+                ("password_confirm", PASSWORD),
+            ],
+        )
+        .await
+    }
+
+    pub async fn log_in(&mut self, email: &str, password: &str) -> Reply {
+        self.submit("/login", "/login", &[("email", email), ("password", password)]).await
+    }
+
+    /// Re-enters the password, unlocking sensitive actions.
+    pub async fn sudo(&mut self) -> Reply {
+        self.submit("/account/verify-password", "/account/verify-password", &[("password", PASSWORD)])
+            .await
+    }
+
+    /// Registers a fresh account and logs in as it.
+    pub async fn sign_up(&mut self, email: &str, username: &str) -> Reply {
+        self.register(email, username).await;
+        self.log_in(email, PASSWORD).await
+    }
+}
+
+static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Collects every log line so tests can look for the ones they expect.
+struct CaptureLogger;
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let line = format!("{} {}", record.level(), record.args());
+        LOGS.lock().unwrap().push(line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the capturing logger; call before the code under test runs.
+pub fn capture_logs() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&CaptureLogger).expect("no other logger in tests");
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// Log lines captured so far that contain `needle`. Tests run in parallel,
+/// so needles should be specific to the test.
+pub fn logged(needle: &str) -> Vec<String> {
+    LOGS.lock()
+        .unwrap()
+        .iter()
+        .filter(|line| line.contains(needle))
+        .cloned()
+        .collect()
+}